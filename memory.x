@@ -2,6 +2,8 @@ MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
   FLASH : ORIGIN = 0x08000000, LENGTH = 128K
+  /* Flash sector 5 holds the persistent configuration, see src/lib/config.rs */
+  CONFIG : ORIGIN = 0x08020000, LENGTH = 128K
   RAM : ORIGIN = 0x20000000, LENGTH = 32K
 }
 
@@ -9,3 +11,5 @@ MEMORY
 /* The stack is of the full descending type. */
 /* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
 _stack_start = ORIGIN(RAM) + LENGTH(RAM);
+
+_config_start = ORIGIN(CONFIG);
@@ -4,41 +4,45 @@
 // Halt on panic
 use panic_halt as _; // panic handler
 
-use cortex_m::asm;
-use cortex_m_rt::entry;
+use cortex_m_rt::{entry, exception};
 use stm32f4::stm32f401 as stm32;
 
+use my_hal::clock::{self, delay_ms};
+use my_hal::rcc::Clocks;
+use my_hal::robot::Dir;
 use my_hal::{pins, timers};
 
-fn delay_ms(ms: u32) {
-    asm::delay(16_000 * ms);
-}
-
 #[entry]
 fn main() -> ! {
     let dp = stm32::Peripherals::take().unwrap();
+    let cp = cortex_m::Peripherals::take().unwrap();
+    clock::configure_systick(cp.SYST, clock::RESET_SYSCLK_HZ);
 
     dp.RCC.ahb1enr.modify(|_, w| w.gpioben().enabled());
     dp.RCC.apb1enr.modify(|_, w| w.tim3en().enabled());
 
     pins::configure_motor_pins(&dp.GPIOB);
     let tim3 = dp.TIM3;
-    timers::configure_tim3(&tim3);
+    timers::configure_tim3(&tim3, &Clocks::RESET, timers::DEFAULT_MOTOR_PWM_HZ).unwrap();
     tim3.cr1.modify(|_, w| w.cen().enabled());
 
     loop {
-        use timers::Direction::*;
-        timers::set_left_motor_duty(&tim3, u16::MAX / 2, Forward);
-        timers::set_right_motor_duty(&tim3, u16::MAX / 2, Forward);
+        timers::set_left_motor_duty(&tim3, u16::MAX / 2, Dir::Fd);
+        timers::set_right_motor_duty(&tim3, u16::MAX / 2, Dir::Fd);
         delay_ms(3000);
-        timers::set_left_motor_duty(&tim3, u16::MAX, Forward);
-        timers::set_right_motor_duty(&tim3, u16::MAX, Forward);
+        timers::set_left_motor_duty(&tim3, u16::MAX, Dir::Fd);
+        timers::set_right_motor_duty(&tim3, u16::MAX, Dir::Fd);
         delay_ms(4000);
         for duty in (0..=u16::MAX).step_by(1 << 11).rev() {
-            timers::set_left_motor_duty(&tim3, duty, Forward);
-            timers::set_right_motor_duty(&tim3, duty, Forward);
+            timers::set_left_motor_duty(&tim3, duty, Dir::Fd);
+            timers::set_right_motor_duty(&tim3, duty, Dir::Fd);
             delay_ms(200);
         }
         delay_ms(5000);
     }
 }
+
+#[exception]
+fn SysTick() {
+    clock::systick_interrupt_handler();
+}
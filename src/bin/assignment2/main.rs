@@ -5,17 +5,24 @@
 use panic_halt as _; // panic handler
 
 use cortex_m::interrupt::free;
-use cortex_m_rt::entry;
+use cortex_m_rt::{entry, exception};
 use stm32::interrupt;
 use stm32f4::stm32f401 as stm32;
 
-use my_hal::{distance::G_DISTANCES, pins, timers};
+use my_hal::cruise::CruiseControl;
+use my_hal::distance::{FRONT, G_DISTANCES, LEFT, RIGHT};
+use my_hal::rcc::{configure_clocks, ClockSource, MAX_SYSCLK_HZ};
+use my_hal::robot::Robot;
+use my_hal::{clock, config, pins, timers};
 
 #[entry]
 fn main() -> ! {
     let dp = stm32::Peripherals::take().unwrap();
+    let cp = cortex_m::Peripherals::take().unwrap();
+    clock::configure_systick(cp.SYST, clock::RESET_SYSCLK_HZ);
 
     let rcc = dp.RCC;
+    let clocks = configure_clocks(&rcc, &dp.FLASH, ClockSource::Hsi, Some(MAX_SYSCLK_HZ)).unwrap();
     rcc.ahb1enr.write(|w| {
         w.gpioaen().enabled();
         w.gpioben().enabled()
@@ -24,39 +31,53 @@ fn main() -> ! {
         w.tim3en().enabled();
         w.tim4en().enabled()
     });
-    rcc.apb2enr.write(|w| w.tim9en().enabled());
+    rcc.apb2enr
+        .write(|w| w.tim1en().enabled().tim9en().enabled());
 
     pins::configure_motor_pins(&dp.GPIOB);
 
     pins::configure_ultrasound_pins(&dp.GPIOA, &dp.GPIOB);
 
-    timers::configure_tim3(&dp.TIM3);
-    timers::configure_tim4(&dp.TIM4);
-    timers::configure_tim9(&dp.TIM9);
+    timers::configure_tim3(&dp.TIM3, &clocks, timers::DEFAULT_MOTOR_PWM_HZ).unwrap();
+    timers::configure_tim4(&dp.TIM4, &clocks).unwrap();
+    timers::configure_trigger_timers(&dp.TIM9, &dp.TIM1, &clocks).unwrap();
 
     timers::init_global_timers(dp.TIM4, dp.TIM2, dp.TIM5);
+    timers::init_global_trigger_timers(dp.TIM9, dp.TIM1);
+    // Only the front sensor is used
+    timers::set_trigger_enabled(LEFT, false);
+    timers::set_trigger_enabled(RIGHT, false);
 
     unsafe {
         stm32::NVIC::unmask(stm32::interrupt::TIM4);
+        stm32::NVIC::unmask(stm32::interrupt::TIM1_BRK_TIM9);
     }
 
-    const LEFT_MOTOR_DUTY: u16 = u16::MAX;
-    // adjust max duty to go straight
-    const RIGHT_MOTOR_DUTY: u16 = (u16::MAX as f32 * 0.82) as u16;
+    let mut robot = Robot::default();
+    // The right motor trim keeps it going straight
+    robot.set_config(config::load());
+    // Slow down smoothly to stop 15cm from the obstacle
+    let mut cruise = CruiseControl::new(150, u16::MAX);
 
     loop {
-        let front_dist = free(|cs| G_DISTANCES.borrow(cs).borrow().front.get_distance_cm());
-        let duties = if front_dist < 15 {
-            (0, 0)
-        } else {
-            (LEFT_MOTOR_DUTY, RIGHT_MOTOR_DUTY)
-        };
-        timers::set_left_motor_duty(&dp.TIM3, duties.0, timers::Direction::Forward);
-        timers::set_right_motor_duty(&dp.TIM3, duties.1, timers::Direction::Forward);
+        let now = clock::micros();
+        // Copied out so the motors are driven with the interrupts enabled
+        let front = free(|cs| G_DISTANCES.borrow(cs).borrow()[FRONT].clone());
+        cruise.drive(&mut robot, &front, now);
     }
 }
 
+#[exception]
+fn SysTick() {
+    clock::systick_interrupt_handler();
+}
+
 #[interrupt]
 fn TIM4() {
     timers::tim4_interrupt_handler();
 }
+
+#[interrupt]
+fn TIM1_BRK_TIM9() {
+    timers::tim9_interrupt_handler();
+}
@@ -1,6 +1,7 @@
 #![no_main]
 #![no_std]
 
+use my_hal::rcc::Clocks;
 use my_hal::robot::{Robot, SensorReadings};
 use my_hal::states::State;
 use my_hal::{adc, dma, pins, timers};
@@ -27,7 +28,7 @@ fn main() -> ! {
     pins::configure_motor_pins(&dp.GPIOB);
 
     let tim3 = dp.TIM3;
-    timers::configure_tim3(&tim3);
+    timers::configure_tim3(&tim3, &Clocks::RESET, timers::DEFAULT_MOTOR_PWM_HZ).unwrap();
     tim3.cr1.modify(|_, w| w.cen().enabled());
 
     pins::configure_pa4(&dp.GPIOA);
@@ -1,19 +1,18 @@
 #![no_main]
 #![no_std]
 use cortex_m::asm;
-use cortex_m::interrupt::{free, Mutex};
-use my_hal::robot::{Robot, SensorReadings};
-use my_hal::states::State;
-use my_hal::{
-    adc,
-    distance::{self, DistanceMeasurer},
-    dma, pins, timers,
-};
+use cortex_m::interrupt::Mutex;
+use my_hal::calibration::IrCalibrator;
+use my_hal::protocol::{self, Command, FrameDecoder, Telemetry};
+use my_hal::rcc::{configure_clocks, ClockSource, MAX_SYSCLK_HZ};
+use my_hal::robot::{Encoders, HardwareSensors, MotorControl, Robot, StopMode};
+use my_hal::states::{State, StateMachine};
+use my_hal::{adc, clock, config, distance::DistanceMeasurer, dma, pins, timers, usart};
 
 // Halt on panic
 use panic_halt as _; // panic handler
 
-use cortex_m_rt::entry;
+use cortex_m_rt::{entry, exception};
 use stm32::interrupt;
 use stm32f4::stm32f401 as stm32;
 
@@ -23,8 +22,11 @@ use rtt_target::{rprintln, rtt_init_print};
 fn main() -> ! {
     rtt_init_print!();
     let dp = stm32::Peripherals::take().unwrap();
+    let cp = cortex_m::Peripherals::take().unwrap();
+    clock::configure_systick(cp.SYST, clock::RESET_SYSCLK_HZ);
 
     let rcc = dp.RCC;
+    let clocks = configure_clocks(&rcc, &dp.FLASH, ClockSource::Hsi, Some(MAX_SYSCLK_HZ)).unwrap();
     rcc.ahb1enr.write(|w| {
         w.gpioaen().enabled();
         w.gpioben().enabled();
@@ -38,7 +40,9 @@ fn main() -> ! {
     });
     rcc.apb2enr.write(|w| {
         w.adc1en().enabled();
-        w.tim9en().enabled()
+        w.tim1en().enabled();
+        w.tim9en().enabled();
+        w.usart1en().enabled()
     });
     pins::configure_motor_pins(&dp.GPIOB);
     pins::configure_ultrasound_pins(&dp.GPIOA, &dp.GPIOB);
@@ -46,12 +50,13 @@ fn main() -> ! {
     pins::configure_pa1(&dp.GPIOA);
     pins::configure_pa4(&dp.GPIOA);
     pins::configure_pa5(&dp.GPIOA);
+    pins::configure_usart_pins(&dp.GPIOA);
 
-    timers::configure_tim3(&dp.TIM3);
-    timers::configure_tim4(&dp.TIM4);
+    timers::configure_tim3(&dp.TIM3, &clocks, timers::DEFAULT_MOTOR_PWM_HZ).unwrap();
+    timers::configure_tim4(&dp.TIM4, &clocks).unwrap();
     timers::configure_tim2(&dp.TIM2);
     timers::configure_tim5(&dp.TIM5);
-    timers::configure_tim9(&dp.TIM9);
+    timers::configure_trigger_timers(&dp.TIM9, &dp.TIM1, &clocks).unwrap();
 
     dma::configure_dma2(&dp.DMA2);
     dp.DMA2.st[0].cr.modify(|_, w| w.en().enabled());
@@ -59,41 +64,129 @@ fn main() -> ! {
     adc::configure_adc(&dp.ADC1);
     dp.ADC1.cr2.modify(|_, w| w.swstart().start());
 
+    usart::configure_usart1(&dp.USART1, 115_200, &clocks);
+
     timers::init_global_timers(dp.TIM4, dp.TIM2, dp.TIM5);
+    timers::init_global_trigger_timers(dp.TIM9, dp.TIM1);
+    usart::init_global_usart(dp.USART1);
     unsafe {
         stm32::NVIC::unmask(stm32::interrupt::TIM4);
         stm32::NVIC::unmask(stm32::interrupt::TIM2);
         stm32::NVIC::unmask(stm32::interrupt::TIM5);
+        stm32::NVIC::unmask(stm32::interrupt::TIM1_BRK_TIM9);
+        stm32::NVIC::unmask(stm32::interrupt::USART1);
     }
 
     let mut robot = Robot::default();
-    let mut state = State::FollowingLineAndAvoiding;
+    let mut machine = StateMachine::new(State::FollowingLineAndAvoiding);
+    robot.set_config(config::load());
+    let mut sensors = HardwareSensors {
+        calibration: robot.config().ir_calibration,
+    };
+    // The maneuvers of a number of ticks overshoot less when braking
+    robot.encoders().set_lock_stop_mode(StopMode::Brake);
+
+    let mut decoder = FrameDecoder::new();
+    let mut frame = [0; protocol::MAX_FRAME_LEN];
+    // Only calibrates on `Command::Calibrate`, the robot may not be on the line when it starts
+    let mut calibrator = None;
 
     loop {
-        let readings = unsafe { adc::INFRARED };
-        let (front_dist, left_dist) = free(|cs| {
-            let dis = distance::G_DISTANCES.borrow(cs).borrow();
-            (dis.front.get_distance_cm(), dis.side.get_distance_cm())
-        });
-        let readings = SensorReadings {
-            left_infrared: readings[0],
-            right_infrared: readings[1],
-            front_distance: front_dist,
-            left_distance: left_dist,
+        robot.read_sensors(&mut sensors);
+        // Before the states change the directions the ticks were counted in
+        robot.update_odometry();
+        // rprintln!("{:?}", &machine.state());
+        // rprintln!("{:?}", robot.get_sensor_readings());
+        while let Some(byte) = usart::read() {
+            if let Some(Ok(command)) = decoder.push(byte) {
+                handle_command(
+                    command,
+                    &mut machine,
+                    &mut robot,
+                    &mut calibrator,
+                    &dp.FLASH,
+                );
+            }
+        }
+        if let Some(sweep) = calibrator.as_mut() {
+            if let Some(result) = sweep.poll(&mut robot, adc::read_infrared()) {
+                calibrator = None;
+                // A failed calibration keeps the previous one
+                if let Ok(ir_calibration) = result {
+                    let mut config = *robot.config();
+                    config.ir_calibration = ir_calibration;
+                    robot.set_config(config);
+                    sensors.calibration = ir_calibration;
+                    // Keep running with the new calibration even if it could not be saved
+                    let _ = config::store(&dp.FLASH, &mut robot);
+                }
+            }
+        }
+        // The states are paused while calibrating, as they would drive the motors
+        let state = if calibrator.is_some() {
+            machine.state()
+        } else {
+            machine.process(&mut robot)
         };
-        // rprintln!("{:?}", &state);
-        // rprintln!("{:?}", &readings);
-        robot.update_sensors(readings);
-        state = state.process_state(&mut robot);
+        if usart::is_tx_idle() {
+            let left_motor = robot.left_motor().get_info();
+            let right_motor = robot.right_motor().get_info();
+            let telemetry =
+                Telemetry::new(state, robot.get_sensor_readings(), left_motor, right_motor);
+            let len = telemetry.encode(&mut frame);
+            usart::write(&frame[..len]);
+        }
         // asm::delay(1_000_000);
     }
 }
 
+fn handle_command(
+    command: Command,
+    machine: &mut StateMachine,
+    robot: &mut Robot,
+    calibrator: &mut Option<IrCalibrator>,
+    flash: &stm32::FLASH,
+) {
+    match command {
+        Command::Stop => {
+            *calibrator = None;
+            machine.set_state(robot, State::Stopped);
+        }
+        Command::SetState(new_state) => {
+            *calibrator = None;
+            machine.set_state(robot, new_state);
+        }
+        Command::Calibrate => {
+            machine.set_state(robot, State::Stopped);
+            *calibrator = Some(IrCalibrator::start(robot));
+        }
+        Command::SetParameter(parameter, value) => {
+            let mut config = *robot.config();
+            config.set_parameter(parameter, value);
+            robot.set_config(config);
+        }
+        Command::SaveConfig => {
+            // Nothing to report the failure to yet, the configuration stays in use until reset
+            let _ = config::store(flash, robot);
+        }
+    }
+}
+
+#[exception]
+fn SysTick() {
+    clock::systick_interrupt_handler();
+}
+
 #[interrupt]
 fn TIM4() {
     timers::tim4_interrupt_handler();
 }
 
+#[interrupt]
+fn TIM1_BRK_TIM9() {
+    timers::tim9_interrupt_handler();
+}
+
 #[interrupt]
 fn TIM2() {
     timers::tim2_interrupt_handler();
@@ -103,3 +196,8 @@ fn TIM2() {
 fn TIM5() {
     timers::tim5_interrupt_handler();
 }
+
+#[interrupt]
+fn USART1() {
+    usart::usart1_interrupt_handler();
+}
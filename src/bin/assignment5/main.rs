@@ -4,16 +4,15 @@
 use cortex_m::asm;
 use my_hal::adc;
 use my_hal::dma;
-use my_hal::robot::Robot;
+use my_hal::rcc::Clocks;
 use my_hal::robot::SensorReadings;
+use my_hal::robot::{MotorControl, Robot};
 use my_hal::states::State;
-use my_hal::timers::{G_TIM2, G_TIM5};
 use my_hal::{pins, timers};
 
 // Halt on panic
 use panic_halt as _; // panic handler
 
-use cortex_m::interrupt::free;
 use cortex_m_rt::entry;
 use stm32f4::stm32f401 as stm32;
 
@@ -42,7 +41,7 @@ fn main() -> ! {
     pins::configure_pa4(&dp.GPIOA);
     pins::configure_pa5(&dp.GPIOA);
 
-    timers::configure_tim3(&dp.TIM3);
+    timers::configure_tim3(&dp.TIM3, &Clocks::RESET, timers::DEFAULT_MOTOR_PWM_HZ).unwrap();
     timers::configure_tim2(&dp.TIM2);
     timers::configure_tim5(&dp.TIM5);
 
@@ -84,25 +83,5 @@ fn main() -> ! {
 }
 
 fn should_stop() -> bool {
-    free(|cs| {
-        G_TIM2
-            .borrow(cs)
-            .borrow()
-            .as_ref()
-            .unwrap()
-            .cr1
-            .read()
-            .cen()
-            .is_disabled()
-    }) && free(|cs| {
-        G_TIM5
-            .borrow(cs)
-            .borrow()
-            .as_ref()
-            .unwrap()
-            .cr1
-            .read()
-            .cen()
-            .is_disabled()
-    })
+    !timers::is_left_motor_locked() && !timers::is_right_motor_locked()
 }
@@ -5,12 +5,12 @@
 use core::cell::{Cell, RefCell};
 use core::sync::atomic::{AtomicU32, Ordering};
 
-use my_hal::pins;
+use my_hal::{clock, pins};
 
 // Halt on panic
 use panic_halt as _; // panic handler
 
-use cortex_m::{asm, interrupt as intr, interrupt::Mutex};
+use cortex_m::{interrupt as intr, interrupt::Mutex};
 use cortex_m_rt::{entry, exception};
 use stm32::interrupt;
 use stm32f4::{stm32f401 as stm32, Reg};
@@ -27,6 +27,7 @@ fn main() -> ! {
 
     let dp = stm32::Peripherals::take().unwrap();
     let cp = cortex_m::Peripherals::take().unwrap();
+    clock::configure_systick(cp.SYST, clock::RESET_SYSCLK_HZ);
 
     dp.RCC.ahb1enr.modify(|_, w| w.gpiocen().enabled());
     dp.RCC.apb1enr.modify(|_, w| w.tim2en().enabled());
@@ -66,7 +67,7 @@ fn main() -> ! {
                 .bits())
         );
         rprintln!("Counter: {}", G_INTR_COUNTER.load(Ordering::Relaxed));
-        asm::delay(5_000_000);
+        clock::delay_ms(300);
     }
 }
 
@@ -79,3 +80,8 @@ fn TIM2() {
         tim2.as_mut().unwrap().sr.modify(|_, w| w.uif().clear_bit());
     });
 }
+
+#[exception]
+fn SysTick() {
+    clock::systick_interrupt_handler();
+}
@@ -0,0 +1,184 @@
+//! Runs the state machine from `states.rs` against a simulated robot and writes its trajectory as CSV,
+//! along with the trajectory its odometry estimates.
+//!
+//! cargo run --features simulator --bin simulator --target x86_64-unknown-linux-gnu -- <scenario> [output.csv] [seconds]
+//!
+//! Scenarios: line, pid-line, avoid, wall
+
+mod world;
+
+use std::cell::RefCell;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::rc::Rc;
+use std::{env, process};
+
+use my_hal::line::LineFollower;
+use my_hal::robot::{Dir, Robot};
+use my_hal::states::{State, StateMachine};
+use my_hal::wall::WallFollower;
+
+use world::{Map, Obstacle, Point, SimEncoders, SimMotor, World};
+
+/// An oval track: two 100cm straights joined by half circles of radius 40cm.
+fn oval_track() -> Vec<Point> {
+    let (length, radius) = (100.0, 40.0);
+    let mut line = vec![Point::new(0.0, 0.0), Point::new(length, 0.0)];
+    for i in 1..=18 {
+        let a = -PI / 2.0 + PI * i as f32 / 18.0;
+        line.push(Point::new(
+            length + radius * a.cos(),
+            radius + radius * a.sin(),
+        ));
+    }
+    line.push(Point::new(0.0, 2.0 * radius));
+    for i in 1..=18 {
+        let a = PI / 2.0 + PI * i as f32 / 18.0;
+        line.push(Point::new(radius * a.cos(), radius + radius * a.sin()));
+    }
+    line
+}
+
+/// A room with the robot inside, 20cm from the wall on its left.
+/// The corner cut into the far end has an outside corner between two inside ones.
+fn room() -> Vec<Obstacle> {
+    let corners = [
+        Point::new(-60.0, 20.0),
+        Point::new(200.0, 20.0),
+        Point::new(200.0, -60.0),
+        Point::new(140.0, -60.0),
+        Point::new(140.0, -160.0),
+        Point::new(-60.0, -160.0),
+    ];
+    (0..corners.len())
+        .map(|i| Obstacle::Wall {
+            from: corners[i],
+            to: corners[(i + 1) % corners.len()],
+        })
+        .collect()
+}
+
+fn scenario(name: &str) -> Option<(Map, State)> {
+    let line = oval_track();
+    let scenario = match name {
+        "line" => (
+            Map {
+                line,
+                obstacles: vec![],
+            },
+            State::FollowingLine,
+        ),
+        "pid-line" => (
+            Map {
+                line,
+                obstacles: vec![],
+            },
+            State::PidFollowingLine(LineFollower::default()),
+        ),
+        "avoid" => (
+            Map {
+                line,
+                obstacles: vec![
+                    Obstacle::Circle {
+                        centre: Point::new(60.0, 0.0),
+                        radius: 8.0,
+                    },
+                    Obstacle::Wall {
+                        from: Point::new(-60.0, -40.0),
+                        to: Point::new(160.0, -40.0),
+                    },
+                ],
+            },
+            State::FollowingLineAndAvoiding,
+        ),
+        "wall" => (
+            Map {
+                line: vec![],
+                obstacles: room(),
+            },
+            State::FollowingWall(WallFollower::default()),
+        ),
+        _ => return None,
+    };
+    Some(scenario)
+}
+
+fn write_csv(out: &mut impl Write, world: &World) -> io::Result<()> {
+    writeln!(
+        out,
+        "time_s,x_cm,y_cm,theta_rad,est_x_cm,est_y_cm,est_theta_rad,state,left_infrared,right_infrared,front_cm,left_cm,right_cm,left_duty,right_duty"
+    )?;
+    let signed = |(duty, dir): (u16, Dir)| match dir {
+        Dir::Fd => duty as i32,
+        Dir::Bk => -(duty as i32),
+    };
+    for s in &world.samples {
+        writeln!(
+            out,
+            "{:.3},{:.2},{:.2},{:.4},{:.2},{:.2},{:.4},{},{},{},{},{},{},{},{}",
+            s.time,
+            s.x,
+            s.y,
+            s.theta,
+            s.estimate.x,
+            s.estimate.y,
+            s.estimate.theta,
+            s.state,
+            s.readings.left_infrared,
+            s.readings.right_infrared,
+            s.readings.front_distance,
+            s.readings.left_distance,
+            s.readings.right_distance,
+            signed(s.left_duty),
+            signed(s.right_duty),
+        )?;
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let Some((map, state)) = args.get(1).and_then(|name| scenario(name)) else {
+        eprintln!("usage: simulator <line|pid-line|avoid|wall> [output.csv] [seconds]");
+        process::exit(1);
+    };
+    let seconds: f32 = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(30.0);
+
+    let world = Rc::new(RefCell::new(World::new(map, Point::new(0.0, 0.0), 0.0)));
+    let mut robot = Robot::new(
+        SimMotor {
+            world: world.clone(),
+            wheel: 0,
+        },
+        SimMotor {
+            world: world.clone(),
+            wheel: 1,
+        },
+        SimEncoders {
+            world: world.clone(),
+        },
+    );
+
+    let mut machine = StateMachine::new(state);
+    while world.borrow().time < seconds && machine.state() != State::Stopped {
+        let readings = {
+            let mut world = world.borrow_mut();
+            // Only the name of the state, without the data some of them carry
+            let name = format!("{:?}", machine.state());
+            world.state = name.split('(').next().unwrap_or_default().to_string();
+            world.estimate = robot.get_pose();
+            world.step();
+            world.sensors()
+        };
+        robot.update_sensors(readings);
+        // Before the states change the directions the ticks were counted in
+        robot.update_odometry();
+        machine.process(&mut robot);
+    }
+    let world = world.borrow();
+    match args.get(2) {
+        Some(path) => write_csv(&mut BufWriter::new(File::create(path)?), &world),
+        None => write_csv(&mut io::stdout().lock(), &world),
+    }
+}
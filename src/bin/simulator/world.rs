@@ -0,0 +1,361 @@
+use std::cell::RefCell;
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+use my_hal::distance::DistanceStatus;
+use my_hal::drive::DEFAULT_DRIVE_CONFIG;
+use my_hal::odometry::Pose;
+use my_hal::robot::{Dir, Encoders, MotorControl, SensorReadings, StopMode};
+
+/// Simulation time step in seconds.
+pub const DT: f32 = 0.001;
+/// Wheel surface speed at full duty, in cm/s.
+const MAX_WHEEL_SPEED: f32 = 40.0;
+/// The right motor is stronger, which is what the trims in `states.rs` compensate for.
+const RIGHT_MOTOR_GAIN: f32 = 1.15;
+const LINE_HALF_WIDTH: f32 = 0.9;
+/// Infrared sensors relative to the wheel axle centre: forward, and left/right of the centre line.
+const IR_FORWARD: f32 = 6.0;
+const IR_SIDE: f32 = 1.5;
+const IR_BACKGROUND: u16 = 50;
+/// Readings fall off linearly this far outside the edge of the line.
+const IR_FALLOFF: f32 = 1.5;
+const ULTRASOUND_FORWARD: f32 = 7.0;
+const ULTRASOUND_MAX_CM: f32 = 400.0;
+const ULTRASOUND_MIN_CM: f32 = 2.0;
+const SAMPLE_EVERY_STEPS: u32 = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Point {
+    pub fn new(x: f32, y: f32) -> Self {
+        Self { x, y }
+    }
+
+    fn offset(self, angle: f32, distance: f32) -> Self {
+        Self::new(
+            self.x + distance * angle.cos(),
+            self.y + distance * angle.sin(),
+        )
+    }
+}
+
+/// Obstacles seen by the ultrasound sensors.
+pub enum Obstacle {
+    Circle { centre: Point, radius: f32 },
+    Wall { from: Point, to: Point },
+}
+
+/// The floor and everything standing on it.
+pub struct Map {
+    /// Polyline of the black line on the floor.
+    pub line: Vec<Point>,
+    pub obstacles: Vec<Obstacle>,
+}
+
+impl Map {
+    fn distance_to_line(&self, p: Point) -> f32 {
+        self.line
+            .windows(2)
+            .map(|seg| distance_to_segment(p, seg[0], seg[1]))
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    fn infrared(&self, p: Point) -> u16 {
+        let outside = (self.distance_to_line(p) - LINE_HALF_WIDTH).max(0.0);
+        let strength = (1.0 - outside / IR_FALLOFF).max(0.0);
+        IR_BACKGROUND + ((1000 - IR_BACKGROUND) as f32 * strength) as u16
+    }
+
+    /// Nothing within range gives an out of range echo and anything nearer than the range a too short one,
+    /// like the real sensor.
+    fn ultrasound(&self, from: Point, angle: f32) -> (u16, DistanceStatus) {
+        let dir = Point::new(angle.cos(), angle.sin());
+        let hit = self
+            .obstacles
+            .iter()
+            .filter_map(|o| match *o {
+                Obstacle::Circle { centre, radius } => ray_circle(from, dir, centre, radius),
+                Obstacle::Wall { from: a, to: b } => ray_segment(from, dir, a, b),
+            })
+            .fold(f32::INFINITY, f32::min);
+        if hit < ULTRASOUND_MIN_CM {
+            (ULTRASOUND_MIN_CM as u16, DistanceStatus::TooClose)
+        } else if hit <= ULTRASOUND_MAX_CM {
+            (hit as u16, DistanceStatus::Valid)
+        } else {
+            (ULTRASOUND_MAX_CM as u16, DistanceStatus::OutOfRange)
+        }
+    }
+}
+
+fn distance_to_segment(p: Point, a: Point, b: Point) -> f32 {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let len2 = dx * dx + dy * dy;
+    let t = if len2 == 0.0 {
+        0.0
+    } else {
+        (((p.x - a.x) * dx + (p.y - a.y) * dy) / len2).clamp(0.0, 1.0)
+    };
+    ((a.x + t * dx - p.x).powi(2) + (a.y + t * dy - p.y).powi(2)).sqrt()
+}
+
+fn ray_circle(from: Point, dir: Point, centre: Point, radius: f32) -> Option<f32> {
+    let (ox, oy) = (from.x - centre.x, from.y - centre.y);
+    let b = ox * dir.x + oy * dir.y;
+    let c = ox * ox + oy * oy - radius * radius;
+    let disc = b * b - c;
+    if disc < 0.0 {
+        return None;
+    }
+    let t = -b - disc.sqrt();
+    (t >= 0.0).then_some(t)
+}
+
+fn ray_segment(from: Point, dir: Point, a: Point, b: Point) -> Option<f32> {
+    let (ex, ey) = (b.x - a.x, b.y - a.y);
+    let denom = dir.x * ey - dir.y * ex;
+    if denom.abs() < 1e-6 {
+        return None;
+    }
+    let (wx, wy) = (a.x - from.x, a.y - from.y);
+    let t = (wx * ey - wy * ex) / denom;
+    let u = (wx * dir.y - wy * dir.x) / denom;
+    (t >= 0.0 && (0.0..=1.0).contains(&u)).then_some(t)
+}
+
+#[derive(Default, Clone, Copy)]
+struct Wheel {
+    fd_duty: u16,
+    bk_duty: u16,
+    ticks: u32,
+    /// Distance travelled since the last tick, in cm.
+    partial: f32,
+    lock: Option<u32>,
+}
+
+impl Wheel {
+    fn duty(&self) -> (u16, Dir) {
+        if self.bk_duty == 0 {
+            (self.fd_duty, Dir::Fd)
+        } else {
+            (self.bk_duty, Dir::Bk)
+        }
+    }
+
+    fn speed(&self, gain: f32) -> f32 {
+        let (duty, dir) = self.duty();
+        let speed = duty as f32 / u16::MAX as f32 * MAX_WHEEL_SPEED * gain;
+        match dir {
+            Dir::Fd => speed,
+            Dir::Bk => -speed,
+        }
+    }
+
+    /// Count the ticks for the distance travelled, stopping the wheel like the timer interrupt once a lock completes.
+    fn travel(&mut self, distance: f32) {
+        let cm_per_tick = DEFAULT_DRIVE_CONFIG.cm_per_tick();
+        self.partial += distance.abs();
+        while self.partial >= cm_per_tick {
+            self.partial -= cm_per_tick;
+            self.ticks = self.ticks.wrapping_add(1);
+        }
+        if self.lock == Some(self.ticks) {
+            self.lock = None;
+            self.fd_duty = 0;
+            self.bk_duty = 0;
+        }
+    }
+}
+
+/// One logged step of the simulation.
+pub struct Sample {
+    pub time: f32,
+    pub x: f32,
+    pub y: f32,
+    pub theta: f32,
+    pub estimate: Pose,
+    pub state: String,
+    pub readings: SensorReadings,
+    pub left_duty: (u16, Dir),
+    pub right_duty: (u16, Dir),
+}
+
+pub struct World {
+    pub map: Map,
+    pub time: f32,
+    pub x: f32,
+    pub y: f32,
+    pub theta: f32,
+    /// Name of the state being processed, recorded with every sample.
+    pub state: String,
+    /// The robot's odometry estimate of its pose, recorded with every sample next to the true one.
+    pub estimate: Pose,
+    pub samples: Vec<Sample>,
+    steps: u32,
+    wheels: [Wheel; 2],
+}
+
+impl World {
+    pub fn new(map: Map, start: Point, theta: f32) -> Self {
+        Self {
+            map,
+            time: 0.0,
+            x: start.x,
+            y: start.y,
+            theta,
+            state: String::new(),
+            estimate: Pose::default(),
+            samples: Vec::new(),
+            steps: 0,
+            wheels: Default::default(),
+        }
+    }
+
+    /// Advance the physics by one time step.
+    pub fn step(&mut self) {
+        let left = self.wheels[0].speed(1.0) * DT;
+        let right = self.wheels[1].speed(RIGHT_MOTOR_GAIN) * DT;
+        let distance = (left + right) / 2.0;
+        let dtheta = (right - left) / DEFAULT_DRIVE_CONFIG.track_width_cm;
+        let heading = self.theta + dtheta / 2.0;
+        self.x += distance * heading.cos();
+        self.y += distance * heading.sin();
+        self.theta = (self.theta + dtheta + PI).rem_euclid(2.0 * PI) - PI;
+        self.wheels[0].travel(left);
+        self.wheels[1].travel(right);
+
+        self.time += DT;
+        self.steps += 1;
+        if self.steps.is_multiple_of(SAMPLE_EVERY_STEPS) {
+            self.samples.push(Sample {
+                time: self.time,
+                x: self.x,
+                y: self.y,
+                theta: self.theta,
+                estimate: self.estimate,
+                state: self.state.clone(),
+                readings: self.sensors(),
+                left_duty: self.wheels[0].duty(),
+                right_duty: self.wheels[1].duty(),
+            });
+        }
+    }
+
+    pub fn sensors(&self) -> SensorReadings {
+        let centre = Point::new(self.x, self.y);
+        let ir_centre = centre.offset(self.theta, IR_FORWARD);
+        let front = centre.offset(self.theta, ULTRASOUND_FORWARD);
+        let (front_distance, front_status) = self.map.ultrasound(front, self.theta);
+        let (left_distance, left_status) = self.map.ultrasound(centre, self.theta + PI / 2.0);
+        let (right_distance, right_status) = self.map.ultrasound(centre, self.theta - PI / 2.0);
+        SensorReadings {
+            front_distance,
+            left_distance,
+            right_distance,
+            front_status,
+            left_status,
+            right_status,
+            left_infrared: self
+                .map
+                .infrared(ir_centre.offset(self.theta + PI / 2.0, IR_SIDE)),
+            right_infrared: self
+                .map
+                .infrared(ir_centre.offset(self.theta - PI / 2.0, IR_SIDE)),
+            time_us: (self.time * 1e6) as u64 as u32,
+        }
+    }
+}
+
+/// Simulated motor, `wheel` is 0 for the left one and 1 for the right one.
+pub struct SimMotor {
+    pub world: Rc<RefCell<World>>,
+    pub wheel: usize,
+}
+
+impl MotorControl for SimMotor {
+    fn forward(&mut self, duty: u16) {
+        let wheel = &mut self.world.borrow_mut().wheels[self.wheel];
+        wheel.fd_duty = duty;
+        wheel.bk_duty = 0;
+    }
+
+    fn backward(&mut self, duty: u16) {
+        let wheel = &mut self.world.borrow_mut().wheels[self.wheel];
+        wheel.bk_duty = duty;
+        wheel.fd_duty = 0;
+    }
+
+    /// The wheels have no inertia, so braking is just stopping.
+    fn brake(&mut self) {
+        self.stop();
+    }
+
+    fn get_info(&self) -> (u16, Dir) {
+        self.world.borrow().wheels[self.wheel].duty()
+    }
+
+    fn get_max_duty(&self) -> u16 {
+        u16::MAX
+    }
+}
+
+pub struct SimEncoders {
+    pub world: Rc<RefCell<World>>,
+}
+
+impl SimEncoders {
+    /// Stop the wheel once it turned another `ticks` ticks, or right away for 0, like the timers.
+    fn lock(&mut self, wheel: usize, ticks: u32) {
+        let wheel = &mut self.world.borrow_mut().wheels[wheel];
+        if ticks == 0 {
+            wheel.lock = None;
+            wheel.fd_duty = 0;
+            wheel.bk_duty = 0;
+        } else {
+            wheel.lock = Some(wheel.ticks.wrapping_add(ticks));
+        }
+    }
+
+    fn is_locked(&self, wheel: usize) -> bool {
+        self.world.borrow().wheels[wheel].lock.is_some()
+    }
+}
+
+impl Encoders for SimEncoders {
+    fn lock_left_motor(&mut self, ticks: u32) {
+        self.lock(0, ticks);
+    }
+
+    fn lock_right_motor(&mut self, ticks: u32) {
+        self.lock(1, ticks);
+    }
+
+    fn unlock_motors(&mut self) {
+        for wheel in self.world.borrow_mut().wheels.iter_mut() {
+            wheel.lock = None;
+        }
+    }
+
+    fn set_lock_stop_mode(&mut self, _mode: StopMode) {}
+
+    fn is_left_motor_locked(&self) -> bool {
+        self.is_locked(0)
+    }
+
+    fn is_right_motor_locked(&self) -> bool {
+        self.is_locked(1)
+    }
+
+    fn left_ticks(&self) -> u32 {
+        self.world.borrow().wheels[0].ticks
+    }
+
+    fn right_ticks(&self) -> u32 {
+        self.world.borrow().wheels[1].ticks
+    }
+}
@@ -1,7 +1,13 @@
+use core::ptr;
 use stm32f4::stm32f401::ADC1;
 
 pub static mut INFRARED: [u16; 2] = [0, 0];
 
+/// Read the latest infrared readings copied over by the DMA.
+pub fn read_infrared() -> [u16; 2] {
+    unsafe { ptr::read_volatile(ptr::addr_of!(INFRARED)) }
+}
+
 pub fn configure_adc(adc: &ADC1) {
     adc.sqr1.write(|w| w.l().bits(1)); // Perform a sequence of 2 conversions
     adc.sqr3.write(|w| unsafe {
@@ -0,0 +1,283 @@
+use crate::motion::{Motion, MotionOutcome};
+use crate::robot::{Encoders, MotorControl, Robot};
+
+/// Upper end of the calibrated infrared readings.
+pub const CALIBRATED_MAX: u16 = 1000;
+/// Smallest difference of the raw readings over the line and the background, out of the 4096 of the ADC.
+/// Below it the sensor did not see the line or is not working, and its range would only scale up noise.
+pub const MIN_CONTRAST: u16 = 200;
+
+/// Range of raw ADC readings seen by one infrared sensor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SensorCalibration {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl Default for SensorCalibration {
+    /// Maps the raw readings 0..1000 onto themselves, so the thresholds behave as before calibrating.
+    fn default() -> Self {
+        Self {
+            min: 0,
+            max: CALIBRATED_MAX,
+        }
+    }
+}
+
+impl SensorCalibration {
+    /// A calibration which has not seen any samples yet.
+    const fn empty() -> Self {
+        Self {
+            min: u16::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn add_sample(&mut self, raw: u16) {
+        self.min = self.min.min(raw);
+        self.max = self.max.max(raw);
+    }
+
+    /// Whether the line and the background were far enough apart to tell them apart, see `MIN_CONTRAST`.
+    pub fn has_contrast(&self) -> bool {
+        self.max >= self.min && self.max - self.min >= MIN_CONTRAST
+    }
+
+    /// Scale a raw reading to 0 (background) ..= 1000 (line).
+    pub fn normalize(&self, raw: u16) -> u16 {
+        if self.max <= self.min {
+            return 0;
+        }
+        let raw = raw.clamp(self.min, self.max);
+        ((raw - self.min) as u32 * CALIBRATED_MAX as u32 / (self.max - self.min) as u32) as u16
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IrCalibration {
+    pub left: SensorCalibration,
+    pub right: SensorCalibration,
+}
+
+impl IrCalibration {
+    /// Normalize the readings of `adc::INFRARED`, left sensor first.
+    pub fn normalize(&self, raw: [u16; 2]) -> [u16; 2] {
+        [self.left.normalize(raw[0]), self.right.normalize(raw[1])]
+    }
+
+    fn add_samples(&mut self, raw: [u16; 2]) {
+        self.left.add_sample(raw[0]);
+        self.right.add_sample(raw[1]);
+    }
+}
+
+/// Number of encoder ticks for each half of a sweep.
+const SWEEP_TICKS: u32 = 4;
+const SWEEP_DUTY: u16 = 50_000;
+/// Longest a sweep may take before the calibration is abandoned.
+const SWEEP_TIMEOUT_MS: u32 = 2000;
+/// Left first, back over the line to the right and back to the centre.
+const SWEEPS: [(u32, bool); 3] = [
+    (SWEEP_TICKS, true),
+    (2 * SWEEP_TICKS, false),
+    (SWEEP_TICKS, true),
+];
+
+/// Why a calibration was not used. The previous one stays in use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalibrationError {
+    /// A sweep did not complete in time, see `MotionOutcome::TimedOut`.
+    TimedOut,
+    /// The readings of a sensor hardly changed over the sweeps, see `MIN_CONTRAST`.
+    LowContrast,
+}
+
+/// Sweeps the robot left and right over the line while sampling the infrared sensors.
+/// The robot should start centred on the line and ends up roughly where it started.
+/// Poll it after every sensor update, like a `Motion`, until it reports how it ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IrCalibrator {
+    calibration: IrCalibration,
+    sweep: usize,
+    motion: Motion,
+}
+
+impl IrCalibrator {
+    pub fn start<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>) -> Self {
+        Self {
+            calibration: IrCalibration {
+                left: SensorCalibration::empty(),
+                right: SensorCalibration::empty(),
+            },
+            sweep: 0,
+            motion: start_sweep(robot, 0),
+        }
+    }
+
+    /// Add the `raw` readings of `adc::INFRARED` and move on to the next sweep once one completes.
+    /// `None` while sweeping, the motors are stopped once it returns the calibration.
+    pub fn poll<M: MotorControl, E: Encoders>(
+        &mut self,
+        robot: &mut Robot<M, E>,
+        raw: [u16; 2],
+    ) -> Option<Result<IrCalibration, CalibrationError>> {
+        self.calibration.add_samples(raw);
+        match self.motion.poll(robot)? {
+            MotionOutcome::Completed if self.sweep + 1 < SWEEPS.len() => {
+                self.sweep += 1;
+                self.motion = start_sweep(robot, self.sweep);
+                None
+            }
+            MotionOutcome::Completed => {
+                robot.left_motor().stop();
+                robot.right_motor().stop();
+                let calibration = self.calibration;
+                if calibration.left.has_contrast() && calibration.right.has_contrast() {
+                    Some(Ok(calibration))
+                } else {
+                    Some(Err(CalibrationError::LowContrast))
+                }
+            }
+            MotionOutcome::TimedOut | MotionOutcome::Cancelled => {
+                Some(Err(CalibrationError::TimedOut))
+            }
+        }
+    }
+}
+
+fn start_sweep<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>, sweep: usize) -> Motion {
+    let (ticks, turn_left) = SWEEPS[sweep];
+    if turn_left {
+        robot.left_motor().backward(SWEEP_DUTY);
+        robot.right_motor().forward(SWEEP_DUTY);
+    } else {
+        robot.left_motor().forward(SWEEP_DUTY);
+        robot.right_motor().backward(SWEEP_DUTY);
+    }
+    Motion::start(robot, Some(ticks), Some(ticks), SWEEP_TIMEOUT_MS)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::mock::{MockEncoders, MockMotor, MockWheel};
+    use crate::robot::{Dir, SensorReadings};
+
+    fn mock_robot<'a>(
+        left: &'a RefCell<MockWheel>,
+        right: &'a RefCell<MockWheel>,
+    ) -> Robot<MockMotor<'a>, MockEncoders<'a>> {
+        Robot::new(
+            MockMotor::new(left),
+            MockMotor::new(right),
+            MockEncoders { left, right },
+        )
+    }
+
+    #[test]
+    fn normalize() {
+        let calibration = SensorCalibration {
+            min: 1000,
+            max: 3000,
+        };
+        assert_eq!(calibration.normalize(1000), 0);
+        assert_eq!(calibration.normalize(2000), 500);
+        assert_eq!(calibration.normalize(3000), CALIBRATED_MAX);
+        // Readings outside of the calibrated range are clamped
+        assert_eq!(calibration.normalize(0), 0);
+        assert_eq!(calibration.normalize(4095), CALIBRATED_MAX);
+
+        let default = SensorCalibration::default();
+        assert_eq!(default.normalize(420), 420);
+        assert_eq!(SensorCalibration::empty().normalize(420), 0);
+        assert_eq!(
+            IrCalibration {
+                left: calibration,
+                right: default,
+            }
+            .normalize([2000, 2000]),
+            [500, CALIBRATED_MAX]
+        );
+    }
+
+    #[test]
+    fn span_check() {
+        let span = |min, max| SensorCalibration { min, max }.has_contrast();
+        assert!(span(1000, 1000 + MIN_CONTRAST));
+        assert!(!span(1000, 1000 + MIN_CONTRAST - 1));
+        assert!(!span(2000, 2000));
+        assert!(!SensorCalibration::empty().has_contrast());
+    }
+
+    #[test]
+    fn sweeps_over_the_line() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        let mut calibrator = IrCalibrator::start(&mut robot);
+        assert_eq!(robot.left_motor().get_info().1, Dir::Bk);
+
+        let mut result = None;
+        for raw in [[300, 3500], [1800, 1900], [3600, 250]]
+            .iter()
+            .cycle()
+            .take(100)
+        {
+            robot.encoders().step();
+            result = calibrator.poll(&mut robot, *raw);
+            if result.is_some() {
+                break;
+            }
+        }
+        let calibration = result.expect("calibration did not complete").unwrap();
+        assert_eq!(
+            calibration.left,
+            SensorCalibration {
+                min: 300,
+                max: 3600
+            }
+        );
+        assert_eq!(
+            calibration.right,
+            SensorCalibration {
+                min: 250,
+                max: 3500
+            }
+        );
+        assert_eq!(left.borrow().ticks, 4 * SWEEP_TICKS);
+        assert_eq!(robot.left_motor().get_info(), (0, Dir::Fd));
+        assert_eq!(robot.right_motor().get_info(), (0, Dir::Fd));
+    }
+
+    #[test]
+    fn a_sensor_without_contrast_is_rejected() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        let mut calibrator = IrCalibrator::start(&mut robot);
+        let result = (0..100)
+            .find_map(|i| {
+                robot.encoders().step();
+                calibrator.poll(&mut robot, [2000 + i % 50, 300 + i * 30])
+            })
+            .expect("calibration did not complete");
+        assert_eq!(result, Err(CalibrationError::LowContrast));
+    }
+
+    #[test]
+    fn a_stuck_sweep_times_out() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        let mut calibrator = IrCalibrator::start(&mut robot);
+        robot.update_sensors(SensorReadings {
+            time_us: SWEEP_TIMEOUT_MS * 1000 + 1,
+            ..Default::default()
+        });
+        assert_eq!(
+            calibrator.poll(&mut robot, [300, 3500]),
+            Some(Err(CalibrationError::TimedOut))
+        );
+        assert_eq!(robot.left_motor().get_info(), (0, Dir::Fd));
+        assert_eq!(left.borrow().lock, None);
+    }
+}
@@ -0,0 +1,230 @@
+//! Monotonic millisecond clock counted by the SysTick interrupt, the one time base of the crate.
+//!
+//! The count only depends on SysTick firing once per millisecond, so SysTick is reprogrammed
+//! through `set_sysclk_hz` whenever the system clock changes, which `rcc::configure_clocks` does.
+//! The clock can be read from interrupt handlers as well as from the main loop.
+
+use core::cell::{Cell, RefCell};
+use core::hint;
+
+use cortex_m::interrupt::{free, CriticalSection, Mutex};
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::{SCB, SYST};
+
+/// The system clock out of reset, the internal 16 MHz oscillator.
+pub const RESET_SYSCLK_HZ: u32 = 16_000_000;
+
+static G_SYST: Mutex<RefCell<Option<SYST>>> = Mutex::new(RefCell::new(None));
+static G_SYSCLK_HZ: Mutex<Cell<u32>> = Mutex::new(Cell::new(RESET_SYSCLK_HZ));
+static G_MILLIS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// A point in time, in milliseconds since the clock was started.
+/// The count wraps around after about 49 days, so only instants less than half of that apart can be compared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instant(u32);
+
+impl Instant {
+    pub const fn from_millis(ms: u32) -> Self {
+        Self(ms)
+    }
+
+    pub fn millis(self) -> u32 {
+        self.0
+    }
+
+    /// Milliseconds from `earlier` to `self`.
+    pub fn millis_since(self, earlier: Instant) -> u32 {
+        self.0.wrapping_sub(earlier.0)
+    }
+
+    /// Milliseconds from `self` to now.
+    pub fn elapsed_ms(self) -> u32 {
+        now().millis_since(self)
+    }
+
+    pub fn add_millis(self, ms: u32) -> Self {
+        Self(self.0.wrapping_add(ms))
+    }
+
+    /// Whether `self` comes after `other`, taking the wrap around into account.
+    pub fn is_after(self, other: Instant) -> bool {
+        (self.0.wrapping_sub(other.0) as i32) > 0
+    }
+}
+
+/// A time to wait for without blocking, checked from a loop or an interrupt handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub const fn at(instant: Instant) -> Self {
+        Self(instant)
+    }
+
+    /// `ms` milliseconds from now.
+    pub fn after_ms(ms: u32) -> Self {
+        Self(now().add_millis(ms))
+    }
+
+    pub fn instant(&self) -> Instant {
+        self.0
+    }
+
+    pub fn has_passed(&self) -> bool {
+        self.has_passed_at(now())
+    }
+
+    pub fn has_passed_at(&self, now: Instant) -> bool {
+        !self.0.is_after(now)
+    }
+
+    /// Milliseconds left, 0 once the deadline has passed.
+    pub fn remaining_ms(&self) -> u32 {
+        self.remaining_ms_at(now())
+    }
+
+    pub fn remaining_ms_at(&self, now: Instant) -> u32 {
+        if self.has_passed_at(now) {
+            0
+        } else {
+            self.0.millis_since(now)
+        }
+    }
+}
+
+/// Fire SysTick every millisecond from the processor clock running at `sysclk_hz`, and keep it
+/// to follow later changes of the system clock.
+pub fn configure_systick(syst: SYST, sysclk_hz: u32) {
+    free(|cs| {
+        G_SYST.borrow(cs).replace(Some(syst));
+        set_sysclk_hz_locked(cs, sysclk_hz);
+    });
+}
+
+/// Tell the clock the processor now runs at `sysclk_hz`.
+/// The count carries on from where it was, only the length of SysTick's period changes.
+pub fn set_sysclk_hz(sysclk_hz: u32) {
+    free(|cs| set_sysclk_hz_locked(cs, sysclk_hz));
+}
+
+fn set_sysclk_hz_locked(cs: &CriticalSection, sysclk_hz: u32) {
+    G_SYSCLK_HZ.borrow(cs).set(sysclk_hz);
+    if let Some(syst) = G_SYST.borrow(cs).borrow_mut().as_mut() {
+        syst.disable_counter();
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(sysclk_hz / 1000 - 1);
+        syst.clear_current();
+        syst.enable_interrupt();
+        syst.enable_counter();
+    }
+}
+
+pub fn systick_interrupt_handler() {
+    free(|cs| {
+        let millis = G_MILLIS.borrow(cs);
+        millis.set(millis.get().wrapping_add(1));
+    });
+}
+
+pub fn now() -> Instant {
+    Instant(free(|cs| G_MILLIS.borrow(cs).get()))
+}
+
+/// Microseconds since the clock was started, wrapping around after about 71 minutes.
+/// This is the time the sensor readings, and so the states and motions, are dated with.
+pub fn micros() -> u32 {
+    free(|cs| {
+        let sysclk_hz = G_SYSCLK_HZ.borrow(cs).get();
+        let reload = sysclk_hz / 1000 - 1;
+        // SysTick counts down from the reload value
+        let elapsed = reload - SYST::get_current().min(reload);
+        let millis = extend_millis(
+            G_MILLIS.borrow(cs).get(),
+            SCB::is_pendst_pending(),
+            elapsed,
+            reload,
+        );
+        let sub_us = (elapsed as u64 * 1_000_000 / sysclk_hz as u64) as u32;
+        millis.wrapping_mul(1000).wrapping_add(sub_us)
+    })
+}
+
+/// The milliseconds counted when SysTick has counted `elapsed` of its period.
+/// A count taken just after SysTick wrapped belongs to the next millisecond if its interrupt is still pending.
+fn extend_millis(millis: u32, pending_tick: bool, elapsed: u32, reload: u32) -> u32 {
+    if pending_tick && elapsed < reload / 2 {
+        millis.wrapping_add(1)
+    } else {
+        millis
+    }
+}
+
+/// Block for at least `ms` milliseconds.
+/// Must not be called with interrupts disabled or from a handler of SysTick's priority or higher,
+/// as the clock would never advance.
+pub fn delay_ms(ms: u32) {
+    // One more millisecond, as the current one may be about to end
+    let deadline = Deadline::after_ms(ms.saturating_add(1));
+    while !deadline.has_passed() {
+        hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_after() {
+        let t = Instant::from_millis(1000);
+        assert!(t.add_millis(1).is_after(t));
+        assert!(!t.is_after(t));
+        assert!(!t.is_after(t.add_millis(1)));
+    }
+
+    #[test]
+    fn is_after_across_the_wrap() {
+        let before = Instant::from_millis(u32::MAX);
+        let after = before.add_millis(1);
+        assert_eq!(after, Instant::from_millis(0));
+        assert!(after.is_after(before));
+        assert!(!before.is_after(after));
+        assert_eq!(after.millis_since(before), 1);
+    }
+
+    #[test]
+    fn deadline_passes_once_reached() {
+        let deadline = Deadline::at(Instant::from_millis(1000));
+        assert!(!deadline.has_passed_at(Instant::from_millis(999)));
+        assert!(deadline.has_passed_at(Instant::from_millis(1000)));
+        assert!(deadline.has_passed_at(Instant::from_millis(1001)));
+    }
+
+    #[test]
+    fn remaining_ms() {
+        let deadline = Deadline::at(Instant::from_millis(1000));
+        assert_eq!(deadline.remaining_ms_at(Instant::from_millis(400)), 600);
+        assert_eq!(deadline.remaining_ms_at(Instant::from_millis(1000)), 0);
+        assert_eq!(deadline.remaining_ms_at(Instant::from_millis(5000)), 0);
+    }
+
+    #[test]
+    fn extend_millis_with_a_pending_tick() {
+        assert_eq!(extend_millis(7, false, 10, 83_999), 7);
+        assert_eq!(extend_millis(7, true, 10, 83_999), 8);
+        // Counted before the wrap, the pending tick is not part of it yet
+        assert_eq!(extend_millis(7, true, 83_990, 83_999), 7);
+    }
+
+    #[test]
+    fn deadline_across_the_wrap() {
+        let deadline = Deadline::at(Instant::from_millis(u32::MAX).add_millis(10));
+        let now = Instant::from_millis(u32::MAX - 5);
+        assert!(!deadline.has_passed_at(now));
+        assert_eq!(deadline.remaining_ms_at(now), 15);
+        assert!(!deadline.has_passed_at(Instant::from_millis(u32::MAX)));
+        assert_eq!(deadline.remaining_ms_at(Instant::from_millis(0)), 9);
+        assert!(deadline.has_passed_at(Instant::from_millis(9)));
+        assert_eq!(deadline.remaining_ms_at(Instant::from_millis(9)), 0);
+    }
+}
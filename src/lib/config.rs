@@ -0,0 +1,385 @@
+use crate::calibration::{IrCalibration, SensorCalibration};
+use crate::crc::crc32;
+
+const MAGIC: u32 = 0x4643_4252; // "RBCF"
+/// Bump whenever the layout of `Config` changes, older blocks are then replaced by the defaults.
+const VERSION: u16 = 2;
+const HEADER_LEN: usize = 8;
+const PAYLOAD_LEN: usize = 32;
+/// Header, payload and CRC, padded to whole words.
+pub const BLOCK_LEN: usize = HEADER_LEN + PAYLOAD_LEN + 4;
+
+/// Tuning parameters which can be changed without reflashing the firmware.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    /// Factor applied to the right motor duty to drive straight.
+    pub right_motor_trim: f32,
+    /// Calibrated infrared reading above which a sensor is touching the line.
+    pub ir_touching_line: u16,
+    /// Calibrated infrared reading above which a sensor is over the line.
+    pub ir_on_line: u16,
+    /// Calibrated infrared reading above which a sensor is centred over the line.
+    pub ir_centred_on_line: u16,
+    pub ir_calibration: IrCalibration,
+    /// Left wheel ticks to turn right around the right wheel.
+    pub turn_right_ticks: u16,
+    /// Left and right wheel ticks to turn left on the spot.
+    pub turn_left_ticks: [u16; 2],
+    /// Ticks to back off after reaching the line.
+    pub back_off_ticks: u16,
+    /// Ticks to drive forward once the side of an obstacle is passed.
+    pub pass_obstacle_ticks: u16,
+    /// Left wheel ticks to turn right when returning to the line with the left sensor centred on it.
+    pub centre_on_line_ticks: u16,
+    /// Left wheel ticks to turn right when returning to the line without it centred.
+    pub search_line_ticks: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            right_motor_trim: 0.82,
+            ir_touching_line: 300,
+            ir_on_line: 600,
+            ir_centred_on_line: 800,
+            ir_calibration: Default::default(),
+            turn_right_ticks: 17,
+            turn_left_ticks: [8, 9],
+            back_off_ticks: 4,
+            pass_obstacle_ticks: 8,
+            centre_on_line_ticks: 4,
+            search_line_ticks: 2,
+        }
+    }
+}
+
+/// Identifies a single field of `Config`, so it can be changed at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parameter {
+    RightMotorTrim = 0,
+    IrTouchingLine = 1,
+    IrOnLine = 2,
+    IrCentredOnLine = 3,
+    TurnRightTicks = 4,
+    TurnLeftLeftTicks = 5,
+    TurnLeftRightTicks = 6,
+    BackOffTicks = 7,
+    PassObstacleTicks = 8,
+    CentreOnLineTicks = 9,
+    SearchLineTicks = 10,
+}
+
+impl Parameter {
+    pub fn from_id(id: u8) -> Option<Self> {
+        use Parameter::*;
+        [
+            RightMotorTrim,
+            IrTouchingLine,
+            IrOnLine,
+            IrCentredOnLine,
+            TurnRightTicks,
+            TurnLeftLeftTicks,
+            TurnLeftRightTicks,
+            BackOffTicks,
+            PassObstacleTicks,
+            CentreOnLineTicks,
+            SearchLineTicks,
+        ]
+        .into_iter()
+        .find(|p| *p as u8 == id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashError {
+    /// The FLASH_SR error flags after a failed erase or program operation.
+    Operation(u32),
+}
+
+impl Config {
+    /// The trim is given as the bits of an `f32`, the other parameters saturate at `u16::MAX`.
+    pub fn set_parameter(&mut self, parameter: Parameter, value: u32) {
+        let value_u16 = value.min(u16::MAX as u32) as u16;
+        match parameter {
+            Parameter::RightMotorTrim => self.right_motor_trim = f32::from_bits(value),
+            Parameter::IrTouchingLine => self.ir_touching_line = value_u16,
+            Parameter::IrOnLine => self.ir_on_line = value_u16,
+            Parameter::IrCentredOnLine => self.ir_centred_on_line = value_u16,
+            Parameter::TurnRightTicks => self.turn_right_ticks = value_u16,
+            Parameter::TurnLeftLeftTicks => self.turn_left_ticks[0] = value_u16,
+            Parameter::TurnLeftRightTicks => self.turn_left_ticks[1] = value_u16,
+            Parameter::BackOffTicks => self.back_off_ticks = value_u16,
+            Parameter::PassObstacleTicks => self.pass_obstacle_ticks = value_u16,
+            Parameter::CentreOnLineTicks => self.centre_on_line_ticks = value_u16,
+            Parameter::SearchLineTicks => self.search_line_ticks = value_u16,
+        }
+    }
+
+    /// Serialize with a header and CRC, the way it is stored in flash.
+    pub fn to_block(self) -> [u8; BLOCK_LEN] {
+        let mut block = [0; BLOCK_LEN];
+        let mut w = Writer {
+            buf: &mut block,
+            pos: 0,
+        };
+        w.u32(MAGIC);
+        w.u16(VERSION);
+        w.u16(PAYLOAD_LEN as u16);
+        w.u32(self.right_motor_trim.to_bits());
+        w.u16(self.ir_touching_line);
+        w.u16(self.ir_on_line);
+        w.u16(self.ir_centred_on_line);
+        for c in [self.ir_calibration.left, self.ir_calibration.right] {
+            w.u16(c.min);
+            w.u16(c.max);
+        }
+        w.u16(self.turn_right_ticks);
+        w.u16(self.turn_left_ticks[0]);
+        w.u16(self.turn_left_ticks[1]);
+        w.u16(self.back_off_ticks);
+        w.u16(self.pass_obstacle_ticks);
+        w.u16(self.centre_on_line_ticks);
+        w.u16(self.search_line_ticks);
+        let crc = crc32(&block[..HEADER_LEN + PAYLOAD_LEN]);
+        block[HEADER_LEN + PAYLOAD_LEN..].copy_from_slice(&crc.to_le_bytes());
+        block
+    }
+
+    /// Returns `None` if the block is corrupted or was written by another version.
+    pub fn from_block(block: &[u8; BLOCK_LEN]) -> Option<Self> {
+        let mut crc = [0; 4];
+        crc.copy_from_slice(&block[HEADER_LEN + PAYLOAD_LEN..]);
+        if crc32(&block[..HEADER_LEN + PAYLOAD_LEN]) != u32::from_le_bytes(crc) {
+            return None;
+        }
+        let mut r = Reader { buf: block, pos: 0 };
+        if r.u32() != MAGIC || r.u16() != VERSION || r.u16() as usize != PAYLOAD_LEN {
+            return None;
+        }
+        Some(Self {
+            right_motor_trim: f32::from_bits(r.u32()),
+            ir_touching_line: r.u16(),
+            ir_on_line: r.u16(),
+            ir_centred_on_line: r.u16(),
+            ir_calibration: IrCalibration {
+                left: SensorCalibration {
+                    min: r.u16(),
+                    max: r.u16(),
+                },
+                right: SensorCalibration {
+                    min: r.u16(),
+                    max: r.u16(),
+                },
+            },
+            turn_right_ticks: r.u16(),
+            turn_left_ticks: [r.u16(), r.u16()],
+            back_off_ticks: r.u16(),
+            pass_obstacle_ticks: r.u16(),
+            centre_on_line_ticks: r.u16(),
+            search_line_ticks: r.u16(),
+        })
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn u16(&mut self, v: u16) {
+        self.buf[self.pos..self.pos + 2].copy_from_slice(&v.to_le_bytes());
+        self.pos += 2;
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf[self.pos..self.pos + 4].copy_from_slice(&v.to_le_bytes());
+        self.pos += 4;
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u16(&mut self) -> u16 {
+        let v = u16::from_le_bytes([self.buf[self.pos], self.buf[self.pos + 1]]);
+        self.pos += 2;
+        v
+    }
+
+    fn u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.buf[self.pos..self.pos + 4]);
+        self.pos += 4;
+        u32::from_le_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A configuration with every field away from its default.
+    fn tuned() -> Config {
+        Config {
+            right_motor_trim: 0.9,
+            ir_touching_line: 310,
+            ir_on_line: 620,
+            ir_centred_on_line: 830,
+            ir_calibration: IrCalibration {
+                left: SensorCalibration { min: 40, max: 900 },
+                right: SensorCalibration { min: 55, max: 870 },
+            },
+            turn_right_ticks: 18,
+            turn_left_ticks: [7, 10],
+            back_off_ticks: 5,
+            pass_obstacle_ticks: 9,
+            centre_on_line_ticks: 3,
+            search_line_ticks: 1,
+        }
+    }
+
+    /// Recompute the CRC after changing the block, so only the change itself can reject it.
+    fn reseal(block: &mut [u8; BLOCK_LEN]) {
+        let crc = crc32(&block[..HEADER_LEN + PAYLOAD_LEN]);
+        block[HEADER_LEN + PAYLOAD_LEN..].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(Config::from_block(&tuned().to_block()), Some(tuned()));
+        let defaults = Config::default();
+        assert_eq!(Config::from_block(&defaults.to_block()), Some(defaults));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut block = tuned().to_block();
+        block[0] ^= 0xFF;
+        reseal(&mut block);
+        assert_eq!(Config::from_block(&block), None);
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut block = tuned().to_block();
+        block[4..6].copy_from_slice(&(VERSION - 1).to_le_bytes());
+        reseal(&mut block);
+        assert_eq!(Config::from_block(&block), None);
+    }
+
+    #[test]
+    fn a_single_flipped_bit_is_caught() {
+        let block = tuned().to_block();
+        for bit in 0..PAYLOAD_LEN * 8 {
+            let mut corrupted = block;
+            corrupted[HEADER_LEN + bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(Config::from_block(&corrupted), None, "bit {bit}");
+        }
+    }
+
+    #[test]
+    fn erased_flash_is_rejected() {
+        assert_eq!(Config::from_block(&[0xFF; BLOCK_LEN]), None);
+    }
+}
+
+#[cfg(feature = "stm32")]
+pub use flash::{load, store};
+
+#[cfg(feature = "stm32")]
+mod flash {
+    use core::{ptr, slice};
+    use cortex_m::interrupt::free;
+    use stm32f4::stm32f401::FLASH;
+
+    use super::{Config, FlashError, BLOCK_LEN};
+    use crate::robot::{Encoders, MotorControl, Robot};
+
+    // The configuration lives in flash sector 5, reserved in memory.x.
+    extern "C" {
+        static _config_start: u32;
+    }
+
+    const FLASH_SECTOR: u8 = 5;
+    const FLASH_KEY1: u32 = 0x4567_0123;
+    const FLASH_KEY2: u32 = 0xCDEF_89AB;
+    /// OPERR, WRPERR, PGAERR, PGPERR and PGSERR in FLASH_SR.
+    const FLASH_SR_ERRORS: u32 = 0xF2;
+
+    /// Load the configuration stored in flash, or the defaults if there is none or it is corrupted.
+    pub fn load() -> Config {
+        let block = unsafe {
+            let start = ptr::addr_of!(_config_start) as *const u8;
+            let mut block = [0; BLOCK_LEN];
+            block.copy_from_slice(slice::from_raw_parts(start, BLOCK_LEN));
+            block
+        };
+        Config::from_block(&block).unwrap_or_default()
+    }
+
+    /// Write the robot's configuration to flash, replacing the stored one.
+    /// Erasing the sector stalls the CPU for up to a couple of seconds, so the motors are stopped first.
+    pub fn store<M: MotorControl, E: Encoders>(
+        flash: &FLASH,
+        robot: &mut Robot<M, E>,
+    ) -> Result<(), FlashError> {
+        robot.left_motor().stop();
+        robot.right_motor().stop();
+        let block = robot.config().to_block();
+        // The keys have to be written back to back, the erase can be interrupted
+        free(|_| unlock(flash));
+        let result = erase_sector(flash).and_then(|_| program(flash, &block));
+        free(|_| flash.cr.modify(|_, w| w.lock().set_bit()));
+        result
+    }
+
+    fn unlock(flash: &FLASH) {
+        if flash.cr.read().lock().bit_is_set() {
+            flash.keyr.write(|w| w.key().bits(FLASH_KEY1));
+            flash.keyr.write(|w| w.key().bits(FLASH_KEY2));
+        }
+    }
+
+    fn erase_sector(flash: &FLASH) -> Result<(), FlashError> {
+        wait_till_ready(flash)?;
+        flash.cr.modify(|_, w| unsafe {
+            w.psize().bits(0b10); // Erase 32 bits at a time
+            w.snb().bits(FLASH_SECTOR);
+            w.ser().set_bit()
+        });
+        flash.cr.modify(|_, w| w.strt().set_bit());
+        let result = wait_till_ready(flash);
+        flash.cr.modify(|_, w| w.ser().clear_bit());
+        result
+    }
+
+    fn program(flash: &FLASH, block: &[u8; BLOCK_LEN]) -> Result<(), FlashError> {
+        flash.cr.modify(|_, w| {
+            w.psize().bits(0b10); // Program 32 bits at a time
+            w.pg().set_bit()
+        });
+        let start = ptr::addr_of!(_config_start) as *mut u32;
+        let result = block.chunks_exact(4).enumerate().try_for_each(|(i, word)| {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            unsafe { ptr::write_volatile(start.add(i), word) };
+            wait_till_ready(flash)
+        });
+        flash.cr.modify(|_, w| w.pg().clear_bit());
+        result
+    }
+
+    fn wait_till_ready(flash: &FLASH) -> Result<(), FlashError> {
+        while flash.sr.read().bsy().bit_is_set() {}
+        let errors = flash.sr.read().bits() & FLASH_SR_ERRORS;
+        if errors != 0 {
+            // The error flags are cleared by writing 1 to them
+            flash.sr.write(|w| unsafe { w.bits(errors) });
+            return Err(FlashError::Operation(errors));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use crate::distance::{DistanceMeasurer, DistanceStatus};
+use crate::robot::{Encoders, MotorControl, Robot};
+
+/// Weight of a new sample in the averaged closing speed.
+const CLOSING_SPEED_SMOOTHING: f32 = 0.5;
+
+/// Adaptive cruise control: drives forward at a cruise duty and slows down smoothly
+/// to stop at a standoff distance from whatever is in front, following it if it moves away.
+/// The duty is proportional to the gap beyond the standoff, minus a term for the closing speed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CruiseControl {
+    standoff_mm: u16,
+    cruise_duty: u16,
+    /// Duty per mm of gap beyond the standoff.
+    kp: f32,
+    /// Duty per mm/s of closing speed.
+    kd: f32,
+    /// Distance and time of the last echo used.
+    last_echo: Option<(u16, u32)>,
+    closing_speed: f32,
+}
+
+impl Default for CruiseControl {
+    /// Stops 15cm from the obstacle, reaching full duty 30cm further away.
+    fn default() -> Self {
+        Self::new(150, u16::MAX)
+    }
+}
+
+impl CruiseControl {
+    pub const fn new(standoff_mm: u16, cruise_duty: u16) -> Self {
+        Self {
+            standoff_mm,
+            cruise_duty,
+            kp: 220.0,
+            kd: 100.0,
+            last_echo: None,
+            closing_speed: 0.0,
+        }
+    }
+
+    pub fn set_gains(&mut self, kp: f32, kd: f32) {
+        self.kp = kp;
+        self.kd = kd;
+    }
+
+    pub fn set_standoff_mm(&mut self, standoff_mm: u16) {
+        self.standoff_mm = standoff_mm;
+    }
+
+    pub fn set_cruise_duty(&mut self, cruise_duty: u16) {
+        self.cruise_duty = cruise_duty;
+    }
+
+    /// How fast the gap is shrinking in mm/s, negative if the obstacle moves away.
+    pub fn get_closing_speed(&self) -> f32 {
+        self.closing_speed
+    }
+
+    /// Compute the forward duty from the front sensor, `now_us` being on the clock of its captures.
+    /// Nothing in range lets the robot cruise, while an obstacle too close or stale or missing readings stop it.
+    pub fn update(&mut self, front: &DistanceMeasurer, now_us: u32) -> u16 {
+        match front.get_status(now_us) {
+            DistanceStatus::Valid => {}
+            DistanceStatus::OutOfRange => {
+                self.reset();
+                return self.cruise_duty;
+            }
+            DistanceStatus::TooClose | DistanceStatus::Stale | DistanceStatus::NoEcho => {
+                self.reset();
+                return 0;
+            }
+        }
+        let distance = front.get_distance_mm();
+        // The closing speed only changes with a new echo, the loop usually runs faster than that
+        if let Some(echo_us) = front.get_last_echo_us() {
+            match self.last_echo {
+                Some((_, last_us)) if last_us == echo_us => {}
+                Some((last_distance, last_us)) => {
+                    let dt = echo_us.wrapping_sub(last_us) as f32 / 1_000_000.0;
+                    let speed = (last_distance as f32 - distance as f32) / dt;
+                    self.closing_speed += CLOSING_SPEED_SMOOTHING * (speed - self.closing_speed);
+                    self.last_echo = Some((distance, echo_us));
+                }
+                None => self.last_echo = Some((distance, echo_us)),
+            }
+        }
+
+        let gap = distance as f32 - self.standoff_mm as f32;
+        let duty = self.kp * gap - self.kd * self.closing_speed;
+        duty.clamp(0.0, self.cruise_duty as f32) as u16
+    }
+
+    /// Drive both motors forward at the duty from `update`, trimming the right one like the configuration says.
+    pub fn drive<M: MotorControl, E: Encoders>(
+        &mut self,
+        robot: &mut Robot<M, E>,
+        front: &DistanceMeasurer,
+        now_us: u32,
+    ) {
+        let duty = self.update(front, now_us);
+        let trim = robot.config().right_motor_trim;
+        robot.left_motor().forward(duty);
+        robot.right_motor().forward((duty as f32 * trim) as u16);
+    }
+
+    /// Forget the closing speed, for example after the obstacle was lost.
+    pub fn reset(&mut self) {
+        self.last_echo = None;
+        self.closing_speed = 0.0;
+    }
+}
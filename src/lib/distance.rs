@@ -1,40 +1,434 @@
 use core::cell::RefCell;
+use core::ops::{Index, IndexMut};
 use cortex_m::interrupt::Mutex;
+
+use crate::filter::{DistanceFilter, FilterConfig, DEFAULT_FILTER};
+use crate::trigger::{TriggerScheduler, DEFAULT_TRIGGER_INTERVAL_MS};
+
+/// Shortest and longest echo pulses of the sensor, about 2cm and 4m.
+/// Without an echo the sensor holds its output high for about 38ms, which is rejected as well.
+const MIN_PULSE_US: u16 = 116;
+const MAX_PULSE_US: u16 = 23_200;
+/// A reading is stale once no pulse arrived for a few turns of its sensor, see `TriggerScheduler::stale_after_us`.
+const DEFAULT_STALE_AFTER_US: u32 =
+    TriggerScheduler::new(SENSOR_COUNT).stale_after_us(DEFAULT_TRIGGER_INTERVAL_MS);
+/// What `get_distance_cm` reports before the first echo, the longest pulse at 58us per cm.
+pub const NO_ECHO_CM: u16 = u16::MAX / 58;
+/// Distance travelled by the echo per microsecond of pulse, there and back, at 20°C.
+const DEFAULT_MM_PER_US: f32 = 343.42 / 2000.0;
+
+/// Speed of sound in dry air in m/s at a temperature in °C.
+pub fn speed_of_sound(celsius: f32) -> f32 {
+    331.3 + 0.606 * celsius
+}
+
+/// Corrects the distances of one sensor: `measured * scale + offset_mm`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DistanceCalibration {
+    pub offset_mm: i16,
+    pub scale: f32,
+}
+
+impl DistanceCalibration {
+    pub const fn new() -> Self {
+        Self {
+            offset_mm: 0,
+            scale: 1.0,
+        }
+    }
+}
+
+impl Default for DistanceCalibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which edge of the echo pulse a capture belongs to, from the level of the pin after the capture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+/// How far a distance reading can be trusted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DistanceStatus {
+    /// The distance was measured recently.
+    Valid,
+    /// No echo arrived for a while, the distance is the last one measured.
+    Stale,
+    /// No echo was ever received, the sensor is probably missing.
+    #[default]
+    NoEcho,
+    /// The last echo was beyond the range of the sensor, usually because nothing is in front of it.
+    OutOfRange,
+    /// The last echo was shorter than the sensor can measure, something is right in front of it.
+    TooClose,
+}
+
+impl DistanceStatus {
+    /// Whether the surroundings are known, an echo out of range meaning nothing is close and
+    /// a too short one that something is closer than the distance can tell.
+    pub fn is_known(self) -> bool {
+        matches!(
+            self,
+            DistanceStatus::Valid | DistanceStatus::OutOfRange | DistanceStatus::TooClose
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct DistanceMeasurer {
-    rising: Option<u16>,
+    rising: Option<u32>,
     time_us: u16,
+    filter: DistanceFilter,
+    mm_per_us: f32,
+    calibration: DistanceCalibration,
+    /// When the last pulse ended, whether or not its width was plausible.
+    last_pulse_us: Option<u32>,
+    /// `Valid`, `OutOfRange` or `TooClose` depending on the width of the last pulse.
+    pulse_status: DistanceStatus,
+    stale_after_us: u32,
 }
 
 impl DistanceMeasurer {
+    /// The filtered distance, `u16::MAX` before the first echo.
+    pub fn get_distance_mm(&self) -> u16 {
+        self.filter
+            .get_output()
+            .map_or(u16::MAX, |width| self.to_mm(width))
+    }
+
+    /// The distance of the last plausible echo, without filtering.
+    pub fn get_raw_distance_mm(&self) -> u16 {
+        self.to_mm(self.time_us)
+    }
+
+    /// The filtered distance, `NO_ECHO_CM` before the first echo.
     pub fn get_distance_cm(&self) -> u16 {
-        self.time_us / 58
+        self.filter
+            .get_output()
+            .map_or(NO_ECHO_CM, |width| self.to_mm(width) / 10)
+    }
+
+    /// The distance of the last plausible echo, without filtering.
+    pub fn get_raw_distance_cm(&self) -> u16 {
+        self.get_raw_distance_mm() / 10
+    }
+
+    fn to_mm(&self, width_us: u16) -> u16 {
+        let mm = width_us as f32 * self.mm_per_us * self.calibration.scale
+            + self.calibration.offset_mm as f32;
+        mm.clamp(0.0, u16::MAX as f32) as u16
+    }
+
+    /// The speed of sound in m/s, see `speed_of_sound` to compensate for the temperature.
+    pub fn set_speed_of_sound(&mut self, meters_per_second: f32) {
+        self.mm_per_us = meters_per_second / 2000.0;
+    }
+
+    pub fn get_calibration(&self) -> DistanceCalibration {
+        self.calibration
+    }
+
+    pub fn set_calibration(&mut self, calibration: DistanceCalibration) {
+        self.calibration = calibration;
+    }
+
+    pub fn get_filter_config(&self) -> FilterConfig {
+        self.filter.get_config()
+    }
+
+    /// Replace the filter, dropping the samples collected so far.
+    pub fn set_filter_config(&mut self, config: FilterConfig) {
+        self.filter = DistanceFilter::new(config);
+    }
+
+    /// When the last echo ended, plausible or not, in microseconds of the clock the captures are dated with.
+    pub fn get_last_echo_us(&self) -> Option<u32> {
+        self.last_pulse_us
+    }
+
+    /// How long after the last echo the distance is stale, which depends on how often the sensor is triggered.
+    pub fn set_stale_after_us(&mut self, stale_after_us: u32) {
+        self.stale_after_us = stale_after_us;
     }
 
-    /// The t should be given in microseconds
-    pub fn update_measurment(&mut self, t: u16) {
-        self.rising = match self.rising {
-            None => Some(t),
-            Some(p) => {
-                self.time_us = t.wrapping_sub(p);
+    /// Status of the distance at `now`, in microseconds of the same clock as the captures.
+    pub fn get_status(&self, now: u32) -> DistanceStatus {
+        match self.last_pulse_us {
+            None => DistanceStatus::NoEcho,
+            Some(t) if now.wrapping_sub(t) > self.stale_after_us => DistanceStatus::Stale,
+            Some(_) => self.pulse_status,
+        }
+    }
+
+    /// The t should be given in microseconds of a free running clock, see `clock::micros`.
+    /// `overcaptured` means an earlier edge was overwritten before it was read, so the pulse
+    /// it started or ended is dropped and the pairing starts over from this edge.
+    pub fn update_measurment(&mut self, t: u32, edge: Edge, overcaptured: bool) {
+        if overcaptured {
+            self.rising = None;
+        }
+        self.rising = match (edge, self.rising) {
+            (Edge::Rising, _) => Some(t),
+            (Edge::Falling, Some(p)) => {
+                let width = t.wrapping_sub(p);
+                if self.get_status(t) == DistanceStatus::Stale {
+                    // The samples from before the gap say nothing about the surroundings now
+                    self.filter.reset();
+                }
+                self.last_pulse_us = Some(t);
+                self.pulse_status = if width < MIN_PULSE_US as u32 {
+                    DistanceStatus::TooClose
+                } else if width > MAX_PULSE_US as u32 {
+                    DistanceStatus::OutOfRange
+                } else {
+                    self.time_us = width as u16;
+                    self.filter.update(self.time_us);
+                    DistanceStatus::Valid
+                };
                 None
             }
+            // The rising edge was missed
+            (Edge::Falling, None) => None,
         }
     }
 
     pub const fn new() -> Self {
+        Self::with_filter(DEFAULT_FILTER)
+    }
+
+    pub const fn with_filter(config: FilterConfig) -> Self {
         Self {
             rising: None,
             time_us: u16::MAX,
+            filter: DistanceFilter::new(config),
+            mm_per_us: DEFAULT_MM_PER_US,
+            calibration: DistanceCalibration::new(),
+            last_pulse_us: None,
+            pulse_status: DistanceStatus::NoEcho,
+            stale_after_us: DEFAULT_STALE_AFTER_US,
         }
     }
 }
 
+/// Indices of the ultrasound sensors in `Measurements`, see `pins::ULTRASOUND_SENSORS` for their wiring.
+pub const FRONT: usize = 0;
+pub const LEFT: usize = 1;
+pub const RIGHT: usize = 2;
+pub const SENSOR_COUNT: usize = 3;
+
+/// One `DistanceMeasurer` for each ultrasound sensor, indexed by `FRONT`, `LEFT` and `RIGHT`.
 pub struct Measurements {
-    pub front: DistanceMeasurer,
-    pub side: DistanceMeasurer,
+    sensors: [DistanceMeasurer; SENSOR_COUNT],
+}
+
+impl Measurements {
+    pub const fn new() -> Self {
+        const MEASURER: DistanceMeasurer = DistanceMeasurer::new();
+        Self {
+            sensors: [MEASURER; SENSOR_COUNT],
+        }
+    }
+
+    /// Compensate the distances of all the sensors for the air temperature in °C.
+    pub fn set_temperature(&mut self, celsius: f32) {
+        let speed = speed_of_sound(celsius);
+        self.sensors
+            .iter_mut()
+            .for_each(|sensor| sensor.set_speed_of_sound(speed));
+    }
+
+    pub fn set_stale_after_us(&mut self, stale_after_us: u32) {
+        self.sensors
+            .iter_mut()
+            .for_each(|sensor| sensor.set_stale_after_us(stale_after_us));
+    }
+
+    pub fn len(&self) -> usize {
+        self.sensors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sensors.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DistanceMeasurer> {
+        self.sensors.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut DistanceMeasurer> {
+        self.sensors.iter_mut()
+    }
 }
 
-pub static G_DISTANCES: Mutex<RefCell<Measurements>> = Mutex::new(RefCell::new(Measurements {
-    front: DistanceMeasurer::new(),
-    side: DistanceMeasurer::new(),
-}));
+impl Default for DistanceMeasurer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Default for Measurements {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index<usize> for Measurements {
+    type Output = DistanceMeasurer;
+
+    fn index(&self, sensor: usize) -> &DistanceMeasurer {
+        &self.sensors[sensor]
+    }
+}
+
+impl IndexMut<usize> for Measurements {
+    fn index_mut(&mut self, sensor: usize) -> &mut DistanceMeasurer {
+        &mut self.sensors[sensor]
+    }
+}
+
+pub static G_DISTANCES: Mutex<RefCell<Measurements>> =
+    Mutex::new(RefCell::new(Measurements::new()));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pulse(measurer: &mut DistanceMeasurer, rising: u32, width: u32) {
+        measurer.update_measurment(rising, Edge::Rising, false);
+        measurer.update_measurment(rising.wrapping_add(width), Edge::Falling, false);
+    }
+
+    #[test]
+    fn no_echo() {
+        let measurer = DistanceMeasurer::new();
+        assert_eq!(measurer.get_status(0), DistanceStatus::NoEcho);
+        assert_eq!(measurer.get_last_echo_us(), None);
+        assert_eq!(measurer.get_distance_mm(), u16::MAX);
+        assert_eq!(measurer.get_distance_cm(), NO_ECHO_CM);
+        assert_eq!(NO_ECHO_CM, 1129);
+    }
+
+    #[test]
+    fn pulse_width() {
+        let mut measurer = DistanceMeasurer::new();
+        pulse(&mut measurer, 1000, 1000);
+        assert_eq!(measurer.get_status(2000), DistanceStatus::Valid);
+        assert_eq!(measurer.get_distance_mm(), 171);
+    }
+
+    #[test]
+    fn pulse_across_the_16_bit_wrap() {
+        let mut measurer = DistanceMeasurer::new();
+        pulse(&mut measurer, u16::MAX as u32 - 500, 1000);
+        assert_eq!(measurer.get_status(66_035), DistanceStatus::Valid);
+        assert_eq!(measurer.get_distance_mm(), 171);
+    }
+
+    #[test]
+    fn pulse_across_the_32_bit_wrap() {
+        let mut measurer = DistanceMeasurer::new();
+        pulse(&mut measurer, u32::MAX - 500, 1000);
+        assert_eq!(measurer.get_status(499), DistanceStatus::Valid);
+        assert_eq!(measurer.get_distance_mm(), 171);
+    }
+
+    #[test]
+    fn readings_go_stale() {
+        let mut measurer = DistanceMeasurer::new();
+        pulse(&mut measurer, 1000, 1000);
+        assert_eq!(
+            measurer.get_status(2000 + DEFAULT_STALE_AFTER_US),
+            DistanceStatus::Valid
+        );
+        assert_eq!(
+            measurer.get_status(2001 + DEFAULT_STALE_AFTER_US),
+            DistanceStatus::Stale
+        );
+        assert_eq!(measurer.get_distance_mm(), 171);
+    }
+
+    #[test]
+    fn slow_triggering_keeps_readings_fresh_longer() {
+        let mut measurer = DistanceMeasurer::new();
+        // Three sensors taking turns every 200ms, each one is triggered every 600ms
+        measurer.set_stale_after_us(TriggerScheduler::new(3).stale_after_us(200));
+        pulse(&mut measurer, 1000, 1000);
+        assert_eq!(measurer.get_status(1_200_000), DistanceStatus::Valid);
+        assert_eq!(measurer.get_status(1_802_000), DistanceStatus::Valid);
+        assert_eq!(measurer.get_status(1_802_001), DistanceStatus::Stale);
+
+        let mut measurements = Measurements::new();
+        measurements.set_stale_after_us(0);
+        pulse(&mut measurements[FRONT], 1000, 1000);
+        assert_eq!(measurements[FRONT].get_status(2001), DistanceStatus::Stale);
+    }
+
+    #[test]
+    fn missed_rising_edge() {
+        let mut measurer = DistanceMeasurer::new();
+        measurer.update_measurment(500, Edge::Falling, false);
+        assert_eq!(measurer.get_status(500), DistanceStatus::NoEcho);
+        pulse(&mut measurer, 40_000, 1000);
+        assert_eq!(measurer.get_distance_mm(), 171);
+    }
+
+    #[test]
+    fn missed_falling_edge() {
+        let mut measurer = DistanceMeasurer::new();
+        measurer.update_measurment(0, Edge::Rising, false);
+        // The pin is high again, so this starts a new pulse rather than ending the first one
+        pulse(&mut measurer, 40_000, 1000);
+        assert_eq!(measurer.get_distance_mm(), 171);
+    }
+
+    #[test]
+    fn overcaptured_falling_edge_is_dropped() {
+        let mut measurer = DistanceMeasurer::new();
+        measurer.update_measurment(0, Edge::Rising, false);
+        measurer.update_measurment(2000, Edge::Falling, true);
+        assert_eq!(measurer.get_status(2000), DistanceStatus::NoEcho);
+        pulse(&mut measurer, 40_000, 1000);
+        assert_eq!(measurer.get_distance_mm(), 171);
+    }
+
+    #[test]
+    fn overcaptured_rising_edge_starts_over() {
+        let mut measurer = DistanceMeasurer::new();
+        measurer.update_measurment(0, Edge::Rising, false);
+        measurer.update_measurment(40_000, Edge::Rising, true);
+        measurer.update_measurment(41_000, Edge::Falling, false);
+        assert_eq!(measurer.get_distance_mm(), 171);
+    }
+
+    #[test]
+    fn widths_out_of_range_are_rejected() {
+        let mut measurer = DistanceMeasurer::new();
+        pulse(&mut measurer, 0, MIN_PULSE_US as u32);
+        assert_eq!(
+            measurer.get_status(MIN_PULSE_US as u32),
+            DistanceStatus::Valid
+        );
+        assert_eq!(measurer.get_distance_mm(), 19);
+        pulse(&mut measurer, 40_000, MAX_PULSE_US as u32);
+        assert_eq!(measurer.get_distance_mm(), 3983);
+
+        for (start, width, status) in [
+            (80_000, MIN_PULSE_US as u32 - 1, DistanceStatus::TooClose),
+            (120_000, MAX_PULSE_US as u32 + 1, DistanceStatus::OutOfRange),
+            // The pulse of a sensor which heard no echo
+            (160_000, 38_000, DistanceStatus::OutOfRange),
+            (200_000, 20, DistanceStatus::TooClose),
+        ] {
+            pulse(&mut measurer, start, width);
+            let end = start + width;
+            assert_eq!(measurer.get_status(end), status);
+            assert!(status.is_known());
+            assert_eq!(measurer.get_last_echo_us(), Some(end));
+            assert_eq!(measurer.get_distance_mm(), 3983);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use core::f32::consts::PI;
+
+use crate::robot::MotorControl;
+use crate::speed::SpeedController;
+
+/// Physical dimensions of the robot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DriveConfig {
+    pub wheel_radius_cm: f32,
+    /// Distance between the centres of the two wheels.
+    pub track_width_cm: f32,
+    /// Encoder ticks per full revolution of a wheel.
+    pub ticks_per_rev: f32,
+    /// The fastest a wheel can be asked to turn, in ticks per second.
+    pub max_wheel_speed: f32,
+}
+
+/// Matches the tick counts used by the assignments: about 21.3cm travelled per 20 ticks.
+pub const DEFAULT_DRIVE_CONFIG: DriveConfig = DriveConfig {
+    wheel_radius_cm: 3.39,
+    track_width_cm: 11.5,
+    ticks_per_rev: 20.0,
+    max_wheel_speed: 60.0,
+};
+
+impl DriveConfig {
+    pub fn cm_per_tick(&self) -> f32 {
+        2.0 * PI * self.wheel_radius_cm / self.ticks_per_rev
+    }
+}
+
+/// Drives the robot by linear and angular velocity instead of per wheel duties.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DifferentialDrive {
+    config: DriveConfig,
+    left: SpeedController,
+    right: SpeedController,
+}
+
+impl DifferentialDrive {
+    pub fn new(config: DriveConfig, left: SpeedController, right: SpeedController) -> Self {
+        Self {
+            config,
+            left,
+            right,
+        }
+    }
+
+    pub fn config(&self) -> &DriveConfig {
+        &self.config
+    }
+
+    /// The v should be given in cm/s and the omega in rad/s, counter-clockwise positive.
+    /// If a wheel would exceed the maximum speed both are scaled down to keep the curvature.
+    pub fn set_velocity(&mut self, v: f32, omega: f32) {
+        let (left, right) = self.wheel_speeds(v, omega);
+        self.left.set_target(left);
+        self.right.set_target(right);
+    }
+
+    /// Convert the velocities to left and right wheel speeds in ticks per second.
+    pub fn wheel_speeds(&self, v: f32, omega: f32) -> (f32, f32) {
+        let half_track = self.config.track_width_cm / 2.0;
+        let ticks_per_cm = 1.0 / self.config.cm_per_tick();
+        let left = (v - omega * half_track) * ticks_per_cm;
+        let right = (v + omega * half_track) * ticks_per_cm;
+
+        let fastest = left.abs().max(right.abs());
+        if fastest > self.config.max_wheel_speed {
+            let scale = self.config.max_wheel_speed / fastest;
+            (left * scale, right * scale)
+        } else {
+            (left, right)
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.set_velocity(0.0, 0.0);
+    }
+
+    /// Start both speed controllers over, see `SpeedController::reset`.
+    pub fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+    }
+
+    /// Should be called periodically with the total encoder ticks and the time since the last call in seconds.
+    pub fn update<M: MotorControl>(
+        &mut self,
+        left_motor: &mut M,
+        right_motor: &mut M,
+        ticks: (u32, u32),
+        dt: f32,
+    ) {
+        self.left.update(left_motor, ticks.0, dt);
+        self.right.update(right_motor, ticks.1, dt);
+    }
+
+    /// The last measured wheel speeds in ticks per second, always positive.
+    pub fn get_speeds(&self) -> (f32, f32) {
+        (self.left.get_speed(), self.right.get_speed())
+    }
+
+    pub fn left(&mut self) -> &mut SpeedController {
+        &mut self.left
+    }
+
+    pub fn right(&mut self) -> &mut SpeedController {
+        &mut self.right
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pid::Pid;
+
+    fn drive() -> DifferentialDrive {
+        let speed = SpeedController::new(Pid::new(1.0, 0.0, 0.0));
+        DifferentialDrive::new(DEFAULT_DRIVE_CONFIG, speed, speed)
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "{actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn converts_velocities_to_wheel_speeds() {
+        let drive = drive();
+        let ticks_per_cm = 1.0 / DEFAULT_DRIVE_CONFIG.cm_per_tick();
+
+        let (left, right) = drive.wheel_speeds(20.0, 0.0);
+        assert_close(left, 20.0 * ticks_per_cm);
+        assert_close(right, 20.0 * ticks_per_cm);
+
+        // Turning on the spot anticlockwise, each wheel on a circle of half the track
+        let (left, right) = drive.wheel_speeds(0.0, 1.0);
+        assert_close(left, -5.75 * ticks_per_cm);
+        assert_close(right, 5.75 * ticks_per_cm);
+
+        let (left, right) = drive.wheel_speeds(-10.0, -1.0);
+        assert_close(left, -4.25 * ticks_per_cm);
+        assert_close(right, -15.75 * ticks_per_cm);
+    }
+
+    #[test]
+    fn saturation_keeps_the_curvature() {
+        let drive = drive();
+        let max = DEFAULT_DRIVE_CONFIG.max_wheel_speed;
+        let (v, omega) = (100.0, 2.0);
+        let (left, right) = drive.wheel_speeds(v, omega);
+        assert_close(right, max);
+        assert!(left < right);
+
+        // Both wheels scaled by the same factor keep the ratio and so the radius of the turn
+        let ticks_per_cm = 1.0 / DEFAULT_DRIVE_CONFIG.cm_per_tick();
+        let scale = max / ((v + omega * 5.75) * ticks_per_cm);
+        assert_close(left, (v - omega * 5.75) * ticks_per_cm * scale);
+
+        let (left, right) = drive.wheel_speeds(-200.0, 0.0);
+        assert_close(left, -max);
+        assert_close(right, -max);
+    }
+
+    #[test]
+    fn set_velocity_sets_both_targets() {
+        let mut drive = drive();
+        drive.set_velocity(0.0, -1.0);
+        let (left, right) = drive.wheel_speeds(0.0, -1.0);
+        assert_eq!(drive.left().get_target(), left);
+        assert_eq!(drive.right().get_target(), right);
+        drive.stop();
+        assert_eq!(drive.left().get_target(), 0.0);
+        assert_eq!(drive.right().get_target(), 0.0);
+    }
+}
@@ -0,0 +1,86 @@
+/// Longest median window supported by `DistanceFilter`.
+pub const MAX_MEDIAN_LEN: usize = 7;
+
+/// Stages of a `DistanceFilter`, applied in the order of the fields.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterConfig {
+    /// Number of samples the median is taken over, 1 disables it. Clamped to `MAX_MEDIAN_LEN`.
+    pub median_len: usize,
+    /// Largest change of the output per sample, 0 disables the limit.
+    pub max_step: u16,
+    /// Weight of a new sample in the exponential average, 1.0 disables the smoothing.
+    pub smoothing: f32,
+}
+
+/// A median of three removes single spurious echoes without delaying real changes much.
+pub const DEFAULT_FILTER: FilterConfig = FilterConfig {
+    median_len: 3,
+    max_step: 0,
+    smoothing: 1.0,
+};
+
+/// Median, rate of change limit and exponential smoothing over a stream of samples.
+#[derive(Clone, Copy, Debug)]
+pub struct DistanceFilter {
+    config: FilterConfig,
+    history: [u16; MAX_MEDIAN_LEN],
+    len: usize,
+    next: usize,
+    output: Option<f32>,
+}
+
+impl DistanceFilter {
+    pub const fn new(config: FilterConfig) -> Self {
+        let mut config = config;
+        if config.median_len == 0 {
+            config.median_len = 1;
+        } else if config.median_len > MAX_MEDIAN_LEN {
+            config.median_len = MAX_MEDIAN_LEN;
+        }
+        Self {
+            config,
+            history: [0; MAX_MEDIAN_LEN],
+            len: 0,
+            next: 0,
+            output: None,
+        }
+    }
+
+    pub fn get_config(&self) -> FilterConfig {
+        self.config
+    }
+
+    /// Forget the previous samples, the next one is passed through unchanged.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.next = 0;
+        self.output = None;
+    }
+
+    /// The last output, if there was any sample since the last reset.
+    pub fn get_output(&self) -> Option<u16> {
+        self.output.map(|o| (o + 0.5) as u16)
+    }
+
+    pub fn update(&mut self, sample: u16) -> u16 {
+        let n = self.config.median_len;
+        self.history[self.next] = sample;
+        self.next = (self.next + 1) % n;
+        self.len = (self.len + 1).min(n);
+
+        let mut sorted = self.history;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable();
+        let mut value = sorted[self.len / 2] as f32;
+
+        if let Some(prev) = self.output {
+            if self.config.max_step != 0 {
+                let step = self.config.max_step as f32;
+                value = value.clamp(prev - step, prev + step);
+            }
+            value = prev + self.config.smoothing * (value - prev);
+        }
+        self.output = Some(value);
+        (value + 0.5) as u16
+    }
+}
@@ -0,0 +1,126 @@
+use crate::pid::Pid;
+use crate::robot::{Encoders, MotorControl, Robot, SensorReadings};
+
+/// Below this sum of both infrared readings the line is considered lost.
+const LINE_LOST_SUM: u16 = 600;
+
+/// Follows the line by steering with a PID on the position of the line between the two infrared sensors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineFollower {
+    pid: Pid,
+    base_duty: u16,
+    last_position: f32,
+    last_us: Option<u32>,
+}
+
+impl Default for LineFollower {
+    fn default() -> Self {
+        Self::new(30_000.0, 0.0, 20.0, 48_000)
+    }
+}
+
+impl LineFollower {
+    /// The output of the PID is the duty added to one wheel and subtracted from the other,
+    /// with the gains in seconds.
+    pub const fn new(kp: f32, ki: f32, kd: f32, base_duty: u16) -> Self {
+        Self {
+            pid: Pid::new(kp, ki, kd),
+            base_duty,
+            last_position: 0.0,
+            last_us: None,
+        }
+    }
+
+    pub fn set_gains(&mut self, kp: f32, ki: f32, kd: f32) {
+        self.pid.set_gains(kp, ki, kd);
+    }
+
+    pub fn set_base_duty(&mut self, base_duty: u16) {
+        self.base_duty = base_duty;
+    }
+
+    /// Estimate where the line is, from -1.0 (under the right sensor) to 1.0 (under the left sensor).
+    /// When the line is lost the last known side is kept at full deflection.
+    pub fn line_position(&mut self, readings: &SensorReadings) -> f32 {
+        let left = readings.left_infrared as f32;
+        let right = readings.right_infrared as f32;
+        let sum = readings
+            .left_infrared
+            .saturating_add(readings.right_infrared);
+        let position = if sum < LINE_LOST_SUM {
+            if self.last_position < 0.0 {
+                -1.0
+            } else if self.last_position > 0.0 {
+                1.0
+            } else {
+                0.0
+            }
+        } else {
+            (left - right) / (left + right)
+        };
+        self.last_position = position;
+        position
+    }
+
+    /// Steer the robot towards the line. The PID is stepped by the time since the previous call,
+    /// taken from `SensorReadings::time_us`.
+    pub fn update<M: MotorControl, E: Encoders>(&mut self, robot: &mut Robot<M, E>) {
+        let sr = robot.get_sensor_readings();
+        let now = sr.time_us;
+        let position = self.line_position(sr);
+        let dt_us = self.last_us.map_or(0, |last| now.wrapping_sub(last));
+        self.last_us = Some(now);
+        let max_duty = robot.left_motor().get_max_duty() as f32;
+        self.pid.set_limits(-max_duty, max_duty);
+        let steering = self.pid.update(position, dt_us as f32 / 1e6);
+
+        let base = self.base_duty as f32;
+        robot.left_motor().set_speed((base - steering) as i32);
+        robot.right_motor().set_speed((base + steering) as i32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::mock::{MockEncoders, MockMotor, MockWheel};
+
+    /// The left duty after the line moved from the middle to halfway under the left sensor in `dt_us`.
+    fn steering_after(dt_us: u32) -> i32 {
+        let (left, right) = (RefCell::default(), RefCell::<MockWheel>::default());
+        let mut robot = Robot::new(
+            MockMotor::new(&left),
+            MockMotor::new(&right),
+            MockEncoders {
+                left: &left,
+                right: &right,
+            },
+        );
+        let mut follower = LineFollower::new(0.0, 0.0, 100.0, 30_000);
+        let mut sr = SensorReadings {
+            left_infrared: 500,
+            right_infrared: 500,
+            time_us: 1_000_000,
+            ..Default::default()
+        };
+        robot.update_sensors(sr.clone());
+        follower.update(&mut robot);
+        // Nothing to take the derivative from on the first update
+        assert_eq!(robot.left_motor().get_speed(), 30_000);
+
+        sr.left_infrared = 750;
+        sr.right_infrared = 250;
+        sr.time_us += dt_us;
+        robot.update_sensors(sr);
+        follower.update(&mut robot);
+        30_000 - robot.left_motor().get_speed()
+    }
+
+    #[test]
+    fn derivative_follows_the_time_between_updates() {
+        assert_eq!(steering_after(15_625), 3200);
+        assert_eq!(steering_after(31_250), 1600);
+    }
+}
@@ -0,0 +1,188 @@
+//! Implementations of the hardware traits without any hardware, to run the control logic on the host.
+//!
+//! A motor and its encoder share a `MockWheel`. The wheels only turn when the test says so, every driven
+//! wheel turning one tick per `MockEncoders::step`, and reading them has no side effects.
+//! The wheels have no inertia, so braking and coasting are the same.
+
+use core::cell::RefCell;
+
+use crate::robot::{Dir, Encoders, MotorControl, SensorReadings, SensorSource, StopMode};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MockWheel {
+    pub fd_duty: u16,
+    pub bk_duty: u16,
+    pub ticks: u32,
+    /// The tick count at which the motor is stopped.
+    pub lock: Option<u32>,
+}
+
+impl MockWheel {
+    fn duty(&self) -> (u16, Dir) {
+        if self.bk_duty == 0 {
+            (self.fd_duty, Dir::Fd)
+        } else {
+            (self.bk_duty, Dir::Bk)
+        }
+    }
+
+    /// Turn one tick if the motor is driven.
+    pub fn step(&mut self) {
+        if self.fd_duty > 0 || self.bk_duty > 0 {
+            self.turn(1);
+        }
+    }
+
+    /// Count ticks as if the wheel turned, stopping the motor once the lock completes.
+    pub fn turn(&mut self, ticks: u32) {
+        for _ in 0..ticks {
+            self.ticks = self.ticks.wrapping_add(1);
+            if self.lock == Some(self.ticks) {
+                self.lock = None;
+                self.fd_duty = 0;
+                self.bk_duty = 0;
+            }
+        }
+    }
+}
+
+pub struct MockMotor<'a> {
+    pub wheel: &'a RefCell<MockWheel>,
+    pub max_duty: u16,
+}
+
+impl<'a> MockMotor<'a> {
+    pub fn new(wheel: &'a RefCell<MockWheel>) -> Self {
+        Self {
+            wheel,
+            max_duty: u16::MAX,
+        }
+    }
+}
+
+impl MotorControl for MockMotor<'_> {
+    fn forward(&mut self, duty: u16) {
+        let mut wheel = self.wheel.borrow_mut();
+        wheel.fd_duty = duty.min(self.max_duty);
+        wheel.bk_duty = 0;
+    }
+
+    fn backward(&mut self, duty: u16) {
+        let mut wheel = self.wheel.borrow_mut();
+        wheel.bk_duty = duty.min(self.max_duty);
+        wheel.fd_duty = 0;
+    }
+
+    fn brake(&mut self) {
+        self.stop();
+    }
+
+    fn get_info(&self) -> (u16, Dir) {
+        self.wheel.borrow().duty()
+    }
+
+    fn get_max_duty(&self) -> u16 {
+        self.max_duty
+    }
+}
+
+pub struct MockEncoders<'a> {
+    pub left: &'a RefCell<MockWheel>,
+    pub right: &'a RefCell<MockWheel>,
+}
+
+fn lock(wheel: &RefCell<MockWheel>, ticks: u32) {
+    let mut wheel = wheel.borrow_mut();
+    if ticks == 0 {
+        wheel.lock = None;
+        wheel.fd_duty = 0;
+        wheel.bk_duty = 0;
+    } else {
+        wheel.lock = Some(wheel.ticks.wrapping_add(ticks));
+    }
+}
+
+impl MockEncoders<'_> {
+    /// Let time pass, turning both wheels one tick if their motors are driven.
+    pub fn step(&self) {
+        self.left.borrow_mut().step();
+        self.right.borrow_mut().step();
+    }
+}
+
+impl Encoders for MockEncoders<'_> {
+    fn lock_left_motor(&mut self, ticks: u32) {
+        lock(self.left, ticks);
+    }
+
+    fn lock_right_motor(&mut self, ticks: u32) {
+        lock(self.right, ticks);
+    }
+
+    fn unlock_motors(&mut self) {
+        self.left.borrow_mut().lock = None;
+        self.right.borrow_mut().lock = None;
+    }
+
+    fn set_lock_stop_mode(&mut self, _mode: StopMode) {}
+
+    fn is_left_motor_locked(&self) -> bool {
+        self.left.borrow().lock.is_some()
+    }
+
+    fn is_right_motor_locked(&self) -> bool {
+        self.right.borrow().lock.is_some()
+    }
+
+    fn left_ticks(&self) -> u32 {
+        self.left.borrow().ticks
+    }
+
+    fn right_ticks(&self) -> u32 {
+        self.right.borrow().ticks
+    }
+}
+
+/// Returns the same readings until they are changed.
+#[derive(Debug, Default)]
+pub struct MockSensors {
+    pub readings: SensorReadings,
+}
+
+impl SensorSource for MockSensors {
+    fn read(&mut self) -> SensorReadings {
+        self.readings.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_step_turns_the_wheels() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut motor = MockMotor::new(&left);
+        let mut encoders = MockEncoders {
+            left: &left,
+            right: &right,
+        };
+        motor.forward(30_000);
+        encoders.lock_left_motor(2);
+        for _ in 0..3 {
+            assert_eq!(motor.get_info(), (30_000, Dir::Fd));
+            assert!(encoders.is_left_motor_locked());
+        }
+        assert_eq!(encoders.left_ticks(), 0);
+
+        encoders.step();
+        assert_eq!(encoders.left_ticks(), 1);
+        // The right motor is not driven
+        assert_eq!(encoders.right_ticks(), 0);
+        encoders.step();
+        assert!(!encoders.is_left_motor_locked());
+        assert_eq!(motor.get_info(), (0, Dir::Fd));
+        encoders.step();
+        assert_eq!(encoders.left_ticks(), 2);
+    }
+}
@@ -1,8 +1,35 @@
 #![no_std]
+#[cfg(feature = "stm32")]
 pub mod adc;
+pub mod calibration;
+pub mod clock;
+pub mod config;
+pub mod crc;
+pub mod cruise;
 pub mod distance;
+#[cfg(feature = "stm32")]
 pub mod dma;
+pub mod drive;
+pub mod filter;
+pub mod line;
+#[cfg(any(test, not(feature = "stm32")))]
+pub mod mock;
+pub mod motion;
+pub mod odometry;
+pub mod pid;
+#[cfg(feature = "stm32")]
 pub mod pins;
+pub mod protocol;
+#[cfg(feature = "stm32")]
+pub mod rcc;
 pub mod robot;
+pub mod speed;
+pub mod spiral;
 pub mod states;
+pub mod timebase;
+#[cfg(feature = "stm32")]
 pub mod timers;
+pub mod trigger;
+#[cfg(feature = "stm32")]
+pub mod usart;
+pub mod wall;
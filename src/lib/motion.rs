@@ -0,0 +1,77 @@
+use crate::robot::{Encoders, MotorControl, Robot};
+
+/// How a `Motion` ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MotionOutcome {
+    /// All the locked wheels turned their ticks.
+    Completed,
+    /// The ticks were not counted in time, an encoder is probably disconnected or a wheel blocked.
+    TimedOut,
+    /// Stopped early by `Motion::cancel`.
+    Cancelled,
+}
+
+/// A move of a number of encoder ticks, stopped by the encoders while the main loop keeps running.
+/// Poll it after every sensor update until it reports how it ended.
+/// Time is taken from `SensorReadings::time_us`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Motion {
+    started_us: u32,
+    timeout_us: u32,
+}
+
+impl Motion {
+    /// Lock the wheels which are given a number of ticks. The motors should already be driven.
+    pub fn start<M: MotorControl, E: Encoders>(
+        robot: &mut Robot<M, E>,
+        left_ticks: Option<u32>,
+        right_ticks: Option<u32>,
+        timeout_ms: u32,
+    ) -> Self {
+        if let Some(ticks) = left_ticks {
+            robot.lock_left_motor(ticks);
+        }
+        if let Some(ticks) = right_ticks {
+            robot.lock_right_motor(ticks);
+        }
+        Self {
+            started_us: robot.get_sensor_readings().time_us,
+            timeout_us: timeout_ms.saturating_mul(1000),
+        }
+    }
+
+    /// Time since the motion started, as of the last sensor readings.
+    pub fn elapsed_us<M: MotorControl, E: Encoders>(&self, robot: &Robot<M, E>) -> u32 {
+        robot
+            .get_sensor_readings()
+            .time_us
+            .wrapping_sub(self.started_us)
+    }
+
+    /// `None` while the motion is still going. Both motors are stopped when it times out.
+    pub fn poll<M: MotorControl, E: Encoders>(
+        &self,
+        robot: &mut Robot<M, E>,
+    ) -> Option<MotionOutcome> {
+        if !robot.is_locked() {
+            Some(MotionOutcome::Completed)
+        } else if self.elapsed_us(robot) > self.timeout_us {
+            stop(robot);
+            Some(MotionOutcome::TimedOut)
+        } else {
+            None
+        }
+    }
+
+    /// Stop both motors before the motion completes.
+    pub fn cancel<M: MotorControl, E: Encoders>(&self, robot: &mut Robot<M, E>) -> MotionOutcome {
+        stop(robot);
+        MotionOutcome::Cancelled
+    }
+}
+
+fn stop<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>) {
+    robot.unlock_motors();
+    robot.left_motor().stop();
+    robot.right_motor().stop();
+}
@@ -0,0 +1,146 @@
+use core::f32::consts::PI;
+
+use crate::drive::DriveConfig;
+use crate::robot::Dir;
+
+/// Position in cm and heading in radians, relative to where the odometry was last reset.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pose {
+    pub x: f32,
+    pub y: f32,
+    /// Counter-clockwise from the x axis, kept within -pi..=pi.
+    pub theta: f32,
+}
+
+/// Dead reckoning from the wheel encoder ticks.
+pub struct Odometry {
+    cm_per_tick: f32,
+    track_width_cm: f32,
+    pose: Pose,
+    last_ticks: Option<(u32, u32)>,
+}
+
+impl Odometry {
+    pub fn new(config: &DriveConfig) -> Self {
+        Self {
+            cm_per_tick: config.cm_per_tick(),
+            track_width_cm: config.track_width_cm,
+            pose: Pose::default(),
+            last_ticks: None,
+        }
+    }
+
+    pub fn get_pose(&self) -> Pose {
+        self.pose
+    }
+
+    pub fn reset(&mut self, pose: Pose) {
+        self.pose = pose;
+    }
+
+    /// The encoders can not tell the direction, so it is taken from the motors.
+    pub fn update(&mut self, ticks: (u32, u32), dirs: (Dir, Dir)) {
+        let (left, right) = match self.last_ticks {
+            Some((last_left, last_right)) => (
+                ticks.0.wrapping_sub(last_left),
+                ticks.1.wrapping_sub(last_right),
+            ),
+            None => (0, 0),
+        };
+        self.last_ticks = Some(ticks);
+
+        let left = self.signed_cm(left, dirs.0);
+        let right = self.signed_cm(right, dirs.1);
+        let distance = (left + right) / 2.0;
+        let dtheta = (right - left) / self.track_width_cm;
+        let heading = self.pose.theta + dtheta / 2.0;
+
+        self.pose.x += distance * libm::cosf(heading);
+        self.pose.y += distance * libm::sinf(heading);
+        self.pose.theta = wrap_angle(self.pose.theta + dtheta);
+    }
+
+    fn signed_cm(&self, ticks: u32, dir: Dir) -> f32 {
+        let cm = ticks as f32 * self.cm_per_tick;
+        match dir {
+            Dir::Fd => cm,
+            Dir::Bk => -cm,
+        }
+    }
+}
+
+fn wrap_angle(theta: f32) -> f32 {
+    if theta > PI {
+        theta - 2.0 * PI
+    } else if theta < -PI {
+        theta + 2.0 * PI
+    } else {
+        theta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::DEFAULT_DRIVE_CONFIG;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "{actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn straight_line() {
+        let mut odometry = Odometry::new(&DEFAULT_DRIVE_CONFIG);
+        odometry.update((100, 200), (Dir::Fd, Dir::Fd));
+        assert_eq!(odometry.get_pose(), Pose::default());
+
+        odometry.update((120, 220), (Dir::Fd, Dir::Fd));
+        let pose = odometry.get_pose();
+        assert_close(pose.x, 20.0 * DEFAULT_DRIVE_CONFIG.cm_per_tick());
+        assert_close(pose.y, 0.0);
+        assert_close(pose.theta, 0.0);
+
+        odometry.update((130, 230), (Dir::Bk, Dir::Bk));
+        assert_close(
+            odometry.get_pose().x,
+            10.0 * DEFAULT_DRIVE_CONFIG.cm_per_tick(),
+        );
+    }
+
+    #[test]
+    fn spin_in_place() {
+        let mut odometry = Odometry::new(&DEFAULT_DRIVE_CONFIG);
+        odometry.update((0, 0), (Dir::Bk, Dir::Fd));
+        odometry.update((5, 5), (Dir::Bk, Dir::Fd));
+        let pose = odometry.get_pose();
+        let config = DEFAULT_DRIVE_CONFIG;
+        assert_close(pose.x, 0.0);
+        assert_close(pose.y, 0.0);
+        assert_close(
+            pose.theta,
+            2.0 * 5.0 * config.cm_per_tick() / config.track_width_cm,
+        );
+
+        // Back the other way past the start, the heading stays within -pi..=pi
+        for ticks in [10, 15, 20, 25, 30] {
+            odometry.update((ticks, ticks), (Dir::Fd, Dir::Bk));
+        }
+        let turned = -4.0 * 2.0 * 5.0 * config.cm_per_tick() / config.track_width_cm;
+        assert_close(odometry.get_pose().theta, wrap_angle(turned));
+        assert!(odometry.get_pose().theta.abs() <= PI);
+    }
+
+    #[test]
+    fn ticks_wrap_around() {
+        let mut odometry = Odometry::new(&DEFAULT_DRIVE_CONFIG);
+        odometry.update((u32::MAX - 2, u32::MAX - 2), (Dir::Fd, Dir::Fd));
+        odometry.update((7, 7), (Dir::Fd, Dir::Fd));
+        assert_close(
+            odometry.get_pose().x,
+            10.0 * DEFAULT_DRIVE_CONFIG.cm_per_tick(),
+        );
+    }
+}
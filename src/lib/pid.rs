@@ -0,0 +1,102 @@
+/// A PID controller with output clamping and integral anti-windup.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pid {
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    out_min: f32,
+    out_max: f32,
+    integral: f32,
+    prev_error: Option<f32>,
+}
+
+impl Pid {
+    pub const fn new(kp: f32, ki: f32, kd: f32) -> Self {
+        Self {
+            kp,
+            ki,
+            kd,
+            out_min: f32::MIN,
+            out_max: f32::MAX,
+            integral: 0.0,
+            prev_error: None,
+        }
+    }
+
+    pub const fn with_limits(mut self, out_min: f32, out_max: f32) -> Self {
+        self.out_min = out_min;
+        self.out_max = out_max;
+        self
+    }
+
+    pub fn set_gains(&mut self, kp: f32, ki: f32, kd: f32) {
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+    }
+
+    pub fn set_limits(&mut self, out_min: f32, out_max: f32) {
+        self.out_min = out_min;
+        self.out_max = out_max;
+        self.integral = self.integral.clamp(out_min, out_max);
+    }
+
+    /// Forget the accumulated integral and the previous error.
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.prev_error = None;
+    }
+
+    /// Compute the next output. The dt should be given in seconds.
+    pub fn update(&mut self, error: f32, dt: f32) -> f32 {
+        let derivative = match self.prev_error {
+            Some(prev) if dt > 0.0 => (error - prev) / dt,
+            _ => 0.0,
+        };
+        self.prev_error = Some(error);
+
+        let integral = (self.integral + self.ki * error * dt).clamp(self.out_min, self.out_max);
+        let unclamped = self.kp * error + integral + self.kd * derivative;
+        let output = unclamped.clamp(self.out_min, self.out_max);
+        // Only integrate when it does not push the output further into saturation
+        let saturated_high = unclamped > self.out_max && error > 0.0;
+        let saturated_low = unclamped < self.out_min && error < 0.0;
+        if !saturated_high && !saturated_low {
+            self.integral = integral;
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_is_clamped() {
+        let mut pid = Pid::new(10.0, 0.0, 0.0).with_limits(-50.0, 100.0);
+        assert_eq!(pid.update(5.0, 1.0), 50.0);
+        assert_eq!(pid.update(20.0, 1.0), 100.0);
+        assert_eq!(pid.update(-20.0, 1.0), -50.0);
+    }
+
+    #[test]
+    fn integral_does_not_wind_up_while_saturated() {
+        let mut pid = Pid::new(1.0, 10.0, 0.0).with_limits(0.0, 100.0);
+        for _ in 0..100 {
+            assert_eq!(pid.update(50.0, 0.1), 100.0);
+        }
+        // The integral stopped at 50, a wound up one would hold the output at the limit
+        assert_eq!(pid.update(-5.0, 0.1), 40.0);
+    }
+
+    #[test]
+    fn integral_stays_within_new_limits() {
+        let mut pid = Pid::new(0.0, 1.0, 0.0).with_limits(-1000.0, 1000.0);
+        pid.update(800.0, 1.0);
+        pid.set_limits(0.0, 100.0);
+        assert_eq!(pid.update(0.0, 1.0), 100.0);
+        pid.reset();
+        assert_eq!(pid.update(0.0, 1.0), 0.0);
+    }
+}
@@ -1,5 +1,7 @@
 use stm32f4::stm32f401::{GPIOA, GPIOB};
 
+use crate::distance::SENSOR_COUNT;
+
 /// Configure to drive the left motor forward.
 /// Uses TIM3 CH3.
 fn configure_pb0(port: &GPIOB) {
@@ -38,39 +40,132 @@ pub fn configure_motor_pins(gpiob: &GPIOB) {
     configure_pb5(gpiob);
 }
 
-/// Configure to be the trigger for the front ultrasound.
-/// Uses TIM9 CH1.
-pub fn configure_pa2(port: &GPIOA) {
-    port.moder.modify(|_, w| w.moder2().alternate());
-    port.afrl.modify(|_, w| w.afrl2().af3());
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+    A,
+    B,
 }
 
-/// Configure to be the trigger for the side ultrasound.
-/// Uses TIM9 CH2.
-pub fn configure_pa3(port: &GPIOA) {
-    port.moder.modify(|_, w| w.moder3().alternate());
-    port.afrl.modify(|_, w| w.afrl3().af3());
+/// A pin in alternate function mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pin {
+    pub port: Port,
+    pub number: u8,
+    pub af: u8,
 }
 
-/// Configure to be the echo for the side ultrasound.
-/// Uses TIM4 CH3.
-pub fn configure_pb8(port: &GPIOB) {
-    port.moder.modify(|_, w| w.moder8().alternate());
-    port.afrh.modify(|_, w| w.afrh8().af2());
+/// Timer output sending the trigger pulses of an ultrasound sensor, channels are numbered from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerOutput {
+    Tim9(usize),
+    Tim1(usize),
 }
 
-/// Configure to be the echo for the side ultrasound.
-/// Uses TIM4 CH4.
-pub fn configure_pb9(port: &GPIOB) {
-    port.moder.modify(|_, w| w.moder9().alternate());
-    port.afrh.modify(|_, w| w.afrh9().af2());
+/// How one ultrasound sensor is wired. The echo is always captured by TIM4.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UltrasoundSensor {
+    pub trigger: TriggerOutput,
+    pub trigger_pin: Pin,
+    /// TIM4 channel capturing the echo, numbered from 1.
+    pub echo_channel: usize,
+    pub echo_pin: Pin,
+}
+
+/// All the ultrasound sensors, in the order of their indices in `distance::Measurements`.
+pub const ULTRASOUND_SENSORS: [UltrasoundSensor; SENSOR_COUNT] = [
+    // Front
+    UltrasoundSensor {
+        trigger: TriggerOutput::Tim9(1),
+        trigger_pin: Pin {
+            port: Port::A,
+            number: 2,
+            af: 3,
+        },
+        echo_channel: 3,
+        echo_pin: Pin {
+            port: Port::B,
+            number: 8,
+            af: 2,
+        },
+    },
+    // Left side
+    UltrasoundSensor {
+        trigger: TriggerOutput::Tim9(2),
+        trigger_pin: Pin {
+            port: Port::A,
+            number: 3,
+            af: 3,
+        },
+        echo_channel: 4,
+        echo_pin: Pin {
+            port: Port::B,
+            number: 9,
+            af: 2,
+        },
+    },
+    // Right side
+    UltrasoundSensor {
+        trigger: TriggerOutput::Tim1(1),
+        trigger_pin: Pin {
+            port: Port::A,
+            number: 8,
+            af: 1,
+        },
+        echo_channel: 1,
+        echo_pin: Pin {
+            port: Port::B,
+            number: 6,
+            af: 2,
+        },
+    },
+];
+
+/// Set the mode and alternate function of a pin through the raw register bits,
+/// as the fields are named after the pin numbers.
+macro_rules! configure_alternate {
+    ($port:expr, $pin:expr) => {{
+        let (n, af) = ($pin.number as u32, $pin.af as u32);
+        $port
+            .moder
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << (2 * n)) | (0b10 << (2 * n))) });
+        if n < 8 {
+            $port
+                .afrl
+                .modify(|r, w| unsafe { w.bits(r.bits() & !(0xF << (4 * n)) | (af << (4 * n))) });
+        } else {
+            $port.afrh.modify(|r, w| unsafe {
+                w.bits(r.bits() & !(0xF << (4 * (n - 8))) | (af << (4 * (n - 8))))
+            });
+        }
+    }};
+}
+
+impl Pin {
+    pub fn configure(&self, porta: &GPIOA, portb: &GPIOB) {
+        match self.port {
+            Port::A => configure_alternate!(porta, self),
+            Port::B => configure_alternate!(portb, self),
+        }
+    }
+
+    /// Read the input level, also from interrupt handlers which do not own the port.
+    pub fn is_high(&self) -> bool {
+        let idr = unsafe {
+            match self.port {
+                Port::A => (*GPIOA::ptr()).idr.read().bits(),
+                Port::B => (*GPIOB::ptr()).idr.read().bits(),
+            }
+        };
+        idr & (1 << self.number) != 0
+    }
 }
 
+/// Configure the trigger and echo pins of all the sensors in `ULTRASOUND_SENSORS`.
 pub fn configure_ultrasound_pins(porta: &GPIOA, portb: &GPIOB) {
-    configure_pa2(porta);
-    configure_pa3(porta);
-    configure_pb8(portb);
-    configure_pb9(portb);
+    ULTRASOUND_SENSORS.iter().for_each(|sensor| {
+        sensor.trigger_pin.configure(porta, portb);
+        sensor.echo_pin.configure(porta, portb);
+    });
 }
 
 /// Configure pin for the left infrared sensor.
@@ -98,3 +193,22 @@ pub fn configure_pa1(port: &GPIOA) {
     port.moder.modify(|_, w| w.moder1().alternate());
     port.afrl.modify(|_, w| w.afrl1().af2());
 }
+
+/// Configure to be the transmit line of the serial port.
+/// Uses USART1 TX.
+pub fn configure_pa9(port: &GPIOA) {
+    port.moder.modify(|_, w| w.moder9().alternate());
+    port.afrh.modify(|_, w| w.afrh9().af7());
+}
+
+/// Configure to be the receive line of the serial port.
+/// Uses USART1 RX.
+pub fn configure_pa10(port: &GPIOA) {
+    port.moder.modify(|_, w| w.moder10().alternate());
+    port.afrh.modify(|_, w| w.afrh10().af7());
+}
+
+pub fn configure_usart_pins(porta: &GPIOA) {
+    configure_pa9(porta);
+    configure_pa10(porta);
+}
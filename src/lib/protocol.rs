@@ -0,0 +1,576 @@
+//! Framed binary protocol used over the USART.
+//!
+//! Every frame is a message type byte followed by its fields in little endian and a CRC-32 of both.
+//! The frame is COBS encoded so it contains no zero bytes, and a zero byte terminates it.
+//! This module does not touch any hardware.
+
+use crate::config::Parameter;
+use crate::crc::crc32;
+use crate::distance::DistanceStatus;
+use crate::line::LineFollower;
+use crate::robot::{Dir, SensorReadings};
+use crate::spiral::SpiralSearch;
+use crate::states::State;
+use crate::wall::WallFollower;
+
+/// Longest decoded payload, message type included.
+pub const MAX_PAYLOAD_LEN: usize = 32;
+/// Longest encoded frame, terminator included.
+pub const MAX_FRAME_LEN: usize = cobs_max_len(MAX_PAYLOAD_LEN + 4) + 1;
+
+const TELEMETRY: u8 = 0x01;
+const STOP: u8 = 0x10;
+const SET_STATE: u8 = 0x11;
+const SET_PARAMETER: u8 = 0x12;
+const SAVE_CONFIG: u8 = 0x13;
+const CALIBRATE: u8 = 0x14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The frame did not fit in the receive buffer.
+    Overflow,
+    /// The frame is not valid COBS.
+    Cobs,
+    Crc,
+    UnknownMessage(u8),
+    /// The frame is too short for its message type, or has an unknown field value.
+    Malformed,
+}
+
+/// Commands sent to the robot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Stop,
+    SetState(State),
+    SetParameter(Parameter, u32),
+    /// Write the current configuration to flash.
+    SaveConfig,
+    /// Stop and sweep over the line to calibrate the infrared sensors, see `calibration::IrCalibrator`.
+    Calibrate,
+}
+
+/// Periodic report sent by the robot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Telemetry {
+    pub state: State,
+    pub front_distance: u16,
+    pub left_distance: u16,
+    pub right_distance: u16,
+    pub front_status: DistanceStatus,
+    pub left_status: DistanceStatus,
+    pub right_status: DistanceStatus,
+    pub left_infrared: u16,
+    pub right_infrared: u16,
+    pub left_motor: (u16, Dir),
+    pub right_motor: (u16, Dir),
+}
+
+impl Telemetry {
+    pub fn new(
+        state: State,
+        readings: &SensorReadings,
+        left_motor: (u16, Dir),
+        right_motor: (u16, Dir),
+    ) -> Self {
+        Self {
+            state,
+            front_distance: readings.front_distance,
+            left_distance: readings.left_distance,
+            right_distance: readings.right_distance,
+            front_status: readings.front_status,
+            left_status: readings.left_status,
+            right_status: readings.right_status,
+            left_infrared: readings.left_infrared,
+            right_infrared: readings.right_infrared,
+            left_motor,
+            right_motor,
+        }
+    }
+
+    /// Encode into a complete frame, returns the number of bytes written.
+    pub fn encode(&self, frame: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let mut payload = Payload::new(TELEMETRY);
+        payload.u8(state_id(&self.state));
+        payload.u16(self.front_distance);
+        payload.u16(self.left_distance);
+        payload.u16(self.right_distance);
+        for status in [self.front_status, self.left_status, self.right_status] {
+            payload.u8(status_id(status));
+        }
+        payload.u16(self.left_infrared);
+        payload.u16(self.right_infrared);
+        for (duty, dir) in [self.left_motor, self.right_motor] {
+            payload.u16(duty);
+            payload.u8(dir_id(dir));
+        }
+        payload.encode(frame)
+    }
+}
+
+impl Command {
+    /// Encode into a complete frame, returns the number of bytes written.
+    pub fn encode(&self, frame: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let payload = match *self {
+            Command::Stop => Payload::new(STOP),
+            Command::SetState(state) => {
+                let mut payload = Payload::new(SET_STATE);
+                payload.u8(state_id(&state));
+                payload
+            }
+            Command::SetParameter(parameter, value) => {
+                let mut payload = Payload::new(SET_PARAMETER);
+                payload.u8(parameter as u8);
+                payload.u32(value);
+                payload
+            }
+            Command::SaveConfig => Payload::new(SAVE_CONFIG),
+            Command::Calibrate => Payload::new(CALIBRATE),
+        };
+        payload.encode(frame)
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
+        let (&kind, fields) = payload.split_first().ok_or(ProtocolError::Malformed)?;
+        let command = match (kind, fields) {
+            (STOP, []) => Command::Stop,
+            (SET_STATE, &[id]) => {
+                Command::SetState(state_from_id(id).ok_or(ProtocolError::Malformed)?)
+            }
+            (SET_PARAMETER, &[id, a, b, c, d]) => Command::SetParameter(
+                Parameter::from_id(id).ok_or(ProtocolError::Malformed)?,
+                u32::from_le_bytes([a, b, c, d]),
+            ),
+            (SAVE_CONFIG, []) => Command::SaveConfig,
+            (CALIBRATE, []) => Command::Calibrate,
+            (STOP | SET_STATE | SET_PARAMETER | SAVE_CONFIG | CALIBRATE, _) => {
+                return Err(ProtocolError::Malformed)
+            }
+            (kind, _) => return Err(ProtocolError::UnknownMessage(kind)),
+        };
+        Ok(command)
+    }
+}
+
+/// Collects received bytes into frames and decodes the commands in them.
+pub struct FrameDecoder {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+    overflow: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Feed one received byte, a result is returned once a frame is complete.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Command, ProtocolError>> {
+        if byte != 0 {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        let (len, overflow) = (self.len, self.overflow);
+        self.len = 0;
+        self.overflow = false;
+        if overflow {
+            return Some(Err(ProtocolError::Overflow));
+        }
+        if len == 0 {
+            // Consecutive terminators are used to resynchronise
+            return None;
+        }
+        let mut decoded = [0; MAX_FRAME_LEN];
+        Some(
+            cobs_decode(&self.buf[..len], &mut decoded)
+                .ok_or(ProtocolError::Cobs)
+                .and_then(|len| check_crc(&decoded[..len]))
+                .and_then(Command::decode),
+        )
+    }
+}
+
+/// Builds a payload before it is framed.
+struct Payload {
+    buf: [u8; MAX_PAYLOAD_LEN + 4],
+    len: usize,
+}
+
+impl Payload {
+    fn new(kind: u8) -> Self {
+        let mut payload = Self {
+            buf: [0; MAX_PAYLOAD_LEN + 4],
+            len: 0,
+        };
+        payload.u8(kind);
+        payload
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.bytes(&[v]);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn encode(mut self, frame: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let crc = crc32(&self.buf[..self.len]);
+        self.u32(crc);
+        let len = cobs_encode(&self.buf[..self.len], frame);
+        frame[len] = 0;
+        len + 1
+    }
+}
+
+/// Returns the payload without the CRC if it matches.
+fn check_crc(decoded: &[u8]) -> Result<&[u8], ProtocolError> {
+    if decoded.len() < 4 {
+        return Err(ProtocolError::Malformed);
+    }
+    let (payload, crc) = decoded.split_at(decoded.len() - 4);
+    if crc32(payload) == u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+        Ok(payload)
+    } else {
+        Err(ProtocolError::Crc)
+    }
+}
+
+const fn cobs_max_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// COBS encode `src` into `dst`, which must hold `cobs_max_len(src.len())` bytes.
+/// Returns the encoded length, without a terminator.
+pub fn cobs_encode(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut code_pos = 0;
+    let mut len = 1;
+    let mut code = 1u8;
+    for &byte in src {
+        if byte == 0 {
+            dst[code_pos] = code;
+            code_pos = len;
+            len += 1;
+            code = 1;
+            continue;
+        }
+        dst[len] = byte;
+        len += 1;
+        code += 1;
+        if code == 0xFF {
+            dst[code_pos] = code;
+            code_pos = len;
+            len += 1;
+            code = 1;
+        }
+    }
+    dst[code_pos] = code;
+    len
+}
+
+/// COBS decode `src`, given without its terminator, into `dst`.
+/// Returns the decoded length or `None` if `src` is not valid COBS.
+pub fn cobs_decode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut len = 0;
+    while pos < src.len() {
+        let code = src[pos] as usize;
+        if code == 0 || pos + code > src.len() {
+            return None;
+        }
+        for &byte in &src[pos + 1..pos + code] {
+            if byte == 0 {
+                return None;
+            }
+            *dst.get_mut(len)? = byte;
+            len += 1;
+        }
+        pos += code;
+        if code < 0xFF && pos < src.len() {
+            *dst.get_mut(len)? = 0;
+            len += 1;
+        }
+    }
+    Some(len)
+}
+
+fn state_id(state: &State) -> u8 {
+    match state {
+        State::FollowingLine => 0,
+        State::PidFollowingLine(_) => 1,
+        State::FollowingLineAndAvoiding => 2,
+        State::Forward => 3,
+        State::TurningLeft => 4,
+        State::TurningRight => 5,
+        State::ReturnToLine => 6,
+        State::Avoiding => 7,
+        State::Stopped => 8,
+        State::FollowingWall(_) => 9,
+        // Only reported, maneuvers are started by the states leading to them
+        State::Maneuvering(..) => 10,
+        State::SpiralSearch(_) => 11,
+    }
+}
+
+fn state_from_id(id: u8) -> Option<State> {
+    let state = match id {
+        0 => State::FollowingLine,
+        1 => State::PidFollowingLine(LineFollower::default()),
+        2 => State::FollowingLineAndAvoiding,
+        3 => State::Forward,
+        4 => State::TurningLeft,
+        5 => State::TurningRight,
+        6 => State::ReturnToLine,
+        7 => State::Avoiding,
+        8 => State::Stopped,
+        9 => State::FollowingWall(WallFollower::default()),
+        11 => State::SpiralSearch(SpiralSearch::default()),
+        _ => return None,
+    };
+    Some(state)
+}
+
+fn status_id(status: DistanceStatus) -> u8 {
+    match status {
+        DistanceStatus::Valid => 0,
+        DistanceStatus::Stale => 1,
+        DistanceStatus::NoEcho => 2,
+        DistanceStatus::OutOfRange => 3,
+        DistanceStatus::TooClose => 4,
+    }
+}
+
+fn dir_id(dir: Dir) -> u8 {
+    match dir {
+        Dir::Fd => 0,
+        Dir::Bk => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(src: &[u8]) {
+        let mut encoded = [0; 1024];
+        let len = cobs_encode(src, &mut encoded);
+        assert!(len <= cobs_max_len(src.len()));
+        assert!(!encoded[..len].contains(&0));
+        let mut decoded = [0; 1024];
+        assert_eq!(cobs_decode(&encoded[..len], &mut decoded), Some(src.len()));
+        assert_eq!(&decoded[..src.len()], src);
+    }
+
+    /// Feed a whole frame, returning what the terminator decodes to.
+    fn feed(decoder: &mut FrameDecoder, frame: &[u8]) -> Option<Result<Command, ProtocolError>> {
+        let (&terminator, bytes) = frame.split_last().unwrap();
+        assert_eq!(terminator, 0);
+        for &byte in bytes {
+            assert_eq!(decoder.push(byte), None);
+        }
+        decoder.push(terminator)
+    }
+
+    fn frame(kind: u8, fields: &[u8]) -> ([u8; MAX_FRAME_LEN], usize) {
+        let mut payload = Payload::new(kind);
+        payload.bytes(fields);
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = payload.encode(&mut frame);
+        (frame, len)
+    }
+
+    #[test]
+    fn cobs_encodes_zeros() {
+        let mut encoded = [0; 8];
+        assert_eq!(cobs_encode(&[0], &mut encoded), 2);
+        assert_eq!(encoded[..2], [1, 1]);
+        assert_eq!(cobs_encode(&[0x11, 0x22, 0, 0x33], &mut encoded), 5);
+        assert_eq!(encoded[..5], [3, 0x11, 0x22, 2, 0x33]);
+    }
+
+    #[test]
+    fn cobs_round_trips() {
+        round_trip(&[]);
+        round_trip(&[0]);
+        round_trip(&[0, 0]);
+        round_trip(&[1, 0, 2, 0]);
+        let mut counting = [0; 600];
+        counting
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, byte)| *byte = i as u8);
+        round_trip(&counting);
+    }
+
+    #[test]
+    fn cobs_round_trips_long_runs() {
+        for len in [253, 254, 255, 508, 600] {
+            let run = [0x55; 600];
+            round_trip(&run[..len]);
+            let mut ended = [0x55; 601];
+            ended[len] = 0;
+            round_trip(&ended[..=len]);
+        }
+    }
+
+    #[test]
+    fn cobs_rejects_invalid() {
+        let mut decoded = [0; 8];
+        assert_eq!(cobs_decode(&[3, 1], &mut decoded), None);
+        assert_eq!(cobs_decode(&[2, 1, 0, 1], &mut decoded), None);
+        assert_eq!(cobs_decode(&[5, 1, 2, 3, 4], &mut [0; 2]), None);
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = [
+            Command::Stop,
+            Command::SetState(State::Avoiding),
+            Command::SetState(State::PidFollowingLine(LineFollower::default())),
+            Command::SetParameter(Parameter::RightMotorTrim, 0.82f32.to_bits()),
+            Command::SaveConfig,
+            Command::Calibrate,
+        ];
+        let mut decoder = FrameDecoder::new();
+        let mut frame = [0; MAX_FRAME_LEN];
+        for command in commands {
+            let len = command.encode(&mut frame);
+            assert_eq!(feed(&mut decoder, &frame[..len]), Some(Ok(command)));
+        }
+    }
+
+    #[test]
+    fn telemetry_reports_every_distance_and_status() {
+        let readings = SensorReadings {
+            front_distance: 0x0102,
+            left_distance: 0x0304,
+            right_distance: 0x0506,
+            front_status: DistanceStatus::TooClose,
+            left_status: DistanceStatus::OutOfRange,
+            right_status: DistanceStatus::Stale,
+            left_infrared: 0x0708,
+            right_infrared: 0x090A,
+            ..Default::default()
+        };
+        let telemetry = Telemetry::new(
+            State::Avoiding,
+            &readings,
+            (0x0B0C, Dir::Fd),
+            (0x0D0E, Dir::Bk),
+        );
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = telemetry.encode(&mut frame);
+        assert_eq!(frame[len - 1], 0);
+        let mut decoded = [0; MAX_FRAME_LEN];
+        let decoded_len = cobs_decode(&frame[..len - 1], &mut decoded).unwrap();
+        assert_eq!(
+            check_crc(&decoded[..decoded_len]),
+            Ok(&[
+                TELEMETRY, 7, 0x02, 0x01, 0x04, 0x03, 0x06, 0x05, 4, 3, 1, 0x08, 0x07, 0x0A, 0x09,
+                0x0C, 0x0B, 0, 0x0E, 0x0D, 1,
+            ][..])
+        );
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = Command::SetState(State::Forward).encode(&mut frame);
+        let mut decoded = [0; MAX_FRAME_LEN];
+        let decoded_len = cobs_decode(&frame[..len - 1], &mut decoded).unwrap();
+        decoded[1] ^= 0x01;
+        let len = cobs_encode(&decoded[..decoded_len], &mut frame);
+        frame[len] = 0;
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(
+            feed(&mut decoder, &frame[..=len]),
+            Some(Err(ProtocolError::Crc))
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let mut decoder = FrameDecoder::new();
+        for (kind, fields) in [
+            (STOP, &[1][..]),
+            (SET_STATE, &[]),
+            (SET_STATE, &[10]),
+            (SET_PARAMETER, &[0, 1, 2, 3]),
+            (SET_PARAMETER, &[0xFF, 0, 0, 0, 0]),
+        ] {
+            let (frame, len) = frame(kind, fields);
+            assert_eq!(
+                feed(&mut decoder, &frame[..len]),
+                Some(Err(ProtocolError::Malformed))
+            );
+        }
+        // Too short for a CRC
+        assert_eq!(
+            feed(&mut decoder, &[4, 1, 2, 3, 0]),
+            Some(Err(ProtocolError::Malformed))
+        );
+    }
+
+    #[test]
+    fn unknown_message() {
+        let mut decoder = FrameDecoder::new();
+        let (frame, len) = frame(0x7F, &[1, 2]);
+        assert_eq!(
+            feed(&mut decoder, &frame[..len]),
+            Some(Err(ProtocolError::UnknownMessage(0x7F)))
+        );
+    }
+
+    #[test]
+    fn overflow_then_resync() {
+        let mut decoder = FrameDecoder::new();
+        for _ in 0..=MAX_FRAME_LEN {
+            assert_eq!(decoder.push(0x42), None);
+        }
+        assert_eq!(decoder.push(0), Some(Err(ProtocolError::Overflow)));
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = Command::Stop.encode(&mut frame);
+        assert_eq!(feed(&mut decoder, &frame[..len]), Some(Ok(Command::Stop)));
+    }
+
+    #[test]
+    fn resync_on_next_terminator() {
+        let mut decoder = FrameDecoder::new();
+        // The end of a frame whose start was missed
+        assert_eq!(
+            feed(&mut decoder, &[0x42, 0x13, 0]),
+            Some(Err(ProtocolError::Cobs))
+        );
+        assert_eq!(decoder.push(0), None);
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = Command::SaveConfig.encode(&mut frame);
+        assert_eq!(
+            feed(&mut decoder, &frame[..len]),
+            Some(Ok(Command::SaveConfig))
+        );
+    }
+}
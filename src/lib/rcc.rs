@@ -0,0 +1,215 @@
+//! System clock configuration: HSI or HSE, optionally through the PLL.
+//!
+//! `configure_clocks` returns the resulting frequencies, which the peripherals are then configured from.
+//! The clocks must not change afterwards without configuring the peripherals again.
+
+use stm32f4::stm32f401::{FLASH, RCC};
+
+use crate::clock::{self, RESET_SYSCLK_HZ};
+
+const HSI_HZ: u32 = 16_000_000;
+/// Fastest the STM32F401 runs.
+pub const MAX_SYSCLK_HZ: u32 = 84_000_000;
+/// Fastest APB1 runs. APB2 is kept below it as well, so the ADC, which runs at half of it, stays within its 36 MHz.
+const MAX_PCLK_HZ: u32 = 42_000_000;
+/// The PLL input after the M divider, 2 MHz gives the least jitter and 1 MHz is the lowest allowed.
+const PLL_INPUTS_HZ: [u32; 2] = [2_000_000, 1_000_000];
+const MIN_VCO_HZ: u32 = 192_000_000;
+const MAX_VCO_HZ: u32 = 432_000_000;
+/// The USB clock of the PLL Q output must not go above it.
+const MAX_PLL48_HZ: u32 = 48_000_000;
+/// Highest frequency with each number of flash wait states, at 2.7 V to 3.6 V.
+const FLASH_WAIT_STATE_HZ: u32 = 30_000_000;
+
+/// The oscillator the clocks are derived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// The internal 16 MHz RC oscillator.
+    Hsi,
+    /// An external crystal of the given frequency, from 4 MHz to 26 MHz.
+    Hse(u32),
+}
+
+impl ClockSource {
+    fn hz(self) -> u32 {
+        match self {
+            ClockSource::Hsi => HSI_HZ,
+            ClockSource::Hse(hz) => hz,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockError {
+    /// The HSE frequency is outside of 4 MHz to 26 MHz, or the HSE did not start.
+    Hse,
+    /// The PLL cannot make the requested frequency from the source, or it is above `MAX_SYSCLK_HZ`.
+    Unreachable(u32),
+}
+
+/// The frequencies the clocks run at once configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clocks {
+    pub sysclk_hz: u32,
+    pub hclk_hz: u32,
+    pub pclk1_hz: u32,
+    pub pclk2_hz: u32,
+}
+
+impl Clocks {
+    /// Everything runs from the HSI out of reset.
+    pub const RESET: Clocks = Clocks {
+        sysclk_hz: RESET_SYSCLK_HZ,
+        hclk_hz: RESET_SYSCLK_HZ,
+        pclk1_hz: RESET_SYSCLK_HZ,
+        pclk2_hz: RESET_SYSCLK_HZ,
+    };
+
+    /// The clock of the timers on APB1: TIM2 to TIM5.
+    pub fn timer1_hz(&self) -> u32 {
+        timer_hz(self.hclk_hz, self.pclk1_hz)
+    }
+
+    /// The clock of the timers on APB2: TIM1, TIM9, TIM10 and TIM11.
+    pub fn timer2_hz(&self) -> u32 {
+        timer_hz(self.hclk_hz, self.pclk2_hz)
+    }
+}
+
+/// The timers run at twice their APB clock when it is divided.
+fn timer_hz(hclk_hz: u32, pclk_hz: u32) -> u32 {
+    if pclk_hz == hclk_hz {
+        pclk_hz
+    } else {
+        pclk_hz * 2
+    }
+}
+
+/// Dividers of the PLL, as written in RCC_PLLCFGR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pll {
+    m: u32,
+    n: u32,
+    p: u32,
+    q: u32,
+}
+
+/// Find the PLL dividers making `sysclk_hz` from `input_hz`, if it can be made exactly.
+fn pll_for(input_hz: u32, sysclk_hz: u32) -> Option<Pll> {
+    let pll_input_hz = PLL_INPUTS_HZ.into_iter().find(|hz| input_hz.is_multiple_of(*hz))?;
+    let p = [2, 4, 6, 8].into_iter().find(|p| {
+        let vco_hz = sysclk_hz * p;
+        (MIN_VCO_HZ..=MAX_VCO_HZ).contains(&vco_hz) && vco_hz.is_multiple_of(pll_input_hz)
+    })?;
+    let vco_hz = sysclk_hz * p;
+    Some(Pll {
+        m: input_hz / pll_input_hz,
+        n: vco_hz / pll_input_hz,
+        p,
+        q: vco_hz.div_ceil(MAX_PLL48_HZ).clamp(2, 15),
+    })
+}
+
+/// Bits of a PPRE field dividing the AHB clock by `div`, a power of two from 1 to 16.
+fn ppre_bits(div: u32) -> u32 {
+    match div {
+        1 => 0b000,
+        _ => 0b100 | (div.trailing_zeros() - 1),
+    }
+}
+
+/// Smallest power of two division of `hclk_hz` not above `MAX_PCLK_HZ`.
+fn apb_div(hclk_hz: u32) -> u32 {
+    let mut div = 1;
+    while hclk_hz / div > MAX_PCLK_HZ {
+        div *= 2;
+    }
+    div
+}
+
+/// Run the system from `source`, through the PLL at `sysclk_hz` if given, else directly.
+/// The AHB runs at the system clock and both APBs at most at 42 MHz.
+/// SysTick is reprogrammed for the new system clock, so the millisecond clock keeps its pace.
+pub fn configure_clocks(
+    rcc: &RCC,
+    flash: &FLASH,
+    source: ClockSource,
+    sysclk_hz: Option<u32>,
+) -> Result<Clocks, ClockError> {
+    if let ClockSource::Hse(hz) = source {
+        if !(4_000_000..=26_000_000).contains(&hz) {
+            return Err(ClockError::Hse);
+        }
+    }
+    let pll = match sysclk_hz {
+        Some(hz) if hz > MAX_SYSCLK_HZ => return Err(ClockError::Unreachable(hz)),
+        Some(hz) => Some(pll_for(source.hz(), hz).ok_or(ClockError::Unreachable(hz))?),
+        None => None,
+    };
+    let sysclk_hz = sysclk_hz.unwrap_or(source.hz());
+
+    // Run from the HSI while the PLL is reconfigured
+    rcc.cr.modify(|_, w| w.hsion().set_bit());
+    while rcc.cr.read().hsirdy().bit_is_clear() {}
+    switch_sysclk(rcc, 0b00);
+    rcc.cr.modify(|_, w| w.pllon().clear_bit());
+
+    if let ClockSource::Hse(_) = source {
+        rcc.cr.modify(|_, w| w.hseon().set_bit());
+        // The HSE takes a few ms to start, give up if it does not
+        let started = (0..1_000_000).any(|_| rcc.cr.read().hserdy().bit_is_set());
+        if !started {
+            rcc.cr.modify(|_, w| w.hseon().clear_bit());
+            return Err(ClockError::Hse);
+        }
+    }
+
+    // Any number of wait states will do while running from the HSI
+    let latency = (sysclk_hz - 1) / FLASH_WAIT_STATE_HZ;
+    flash.acr.modify(|r, w| unsafe {
+        // LATENCY, with the prefetch buffer and both caches (PRFTEN, ICEN and DCEN)
+        w.bits(r.bits() & !0xF | latency | 0b111 << 8)
+    });
+
+    let pclk_div = apb_div(sysclk_hz);
+    rcc.cfgr.modify(|r, w| unsafe {
+        // HPRE at 1, PPRE1 and PPRE2 at `pclk_div`
+        let ppre = ppre_bits(pclk_div);
+        w.bits(r.bits() & !0xFCF0 | ppre << 10 | ppre << 13)
+    });
+
+    match pll {
+        Some(pll) => {
+            let source_bit = matches!(source, ClockSource::Hse(_)) as u32;
+            rcc.pllcfgr.modify(|r, w| unsafe {
+                // PLLM, PLLN, PLLP, PLLSRC and PLLQ, the other bits are reserved
+                let fields =
+                    pll.m | pll.n << 6 | (pll.p / 2 - 1) << 16 | source_bit << 22 | pll.q << 24;
+                w.bits(r.bits() & !0x0F43_7FFF | fields)
+            });
+            rcc.cr.modify(|_, w| w.pllon().set_bit());
+            while rcc.cr.read().pllrdy().bit_is_clear() {}
+            switch_sysclk(rcc, 0b10);
+        }
+        None if source == ClockSource::Hsi => {}
+        None => switch_sysclk(rcc, 0b01),
+    }
+    if source != ClockSource::Hsi {
+        rcc.cr.modify(|_, w| w.hsion().clear_bit());
+    }
+    clock::set_sysclk_hz(sysclk_hz);
+
+    Ok(Clocks {
+        sysclk_hz,
+        hclk_hz: sysclk_hz,
+        pclk1_hz: sysclk_hz / pclk_div,
+        pclk2_hz: sysclk_hz / pclk_div,
+    })
+}
+
+/// Select the system clock in RCC_CFGR.SW and wait for RCC_CFGR.SWS to follow.
+fn switch_sysclk(rcc: &RCC, sw: u32) {
+    rcc.cfgr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !0b11 | sw) });
+    while (rcc.cfgr.read().bits() >> 2) & 0b11 != sw {}
+}
@@ -1,103 +1,227 @@
 use core::ptr;
-use cortex_m::interrupt::free;
-use stm32f4::stm32f401::TIM3;
 
-use crate::timers;
+use crate::config::Config;
+use crate::distance::DistanceStatus;
+use crate::drive::{DifferentialDrive, DEFAULT_DRIVE_CONFIG};
+use crate::odometry::{Odometry, Pose};
+use crate::pid::Pid;
+use crate::speed::SpeedController;
+use crate::timebase::{compare_to_duty, duty_to_compare, full_on_compare};
 
 pub static mut INFRARED: [u16; 2] = [0, 0];
 
 type Cm = u16;
 
-#[derive(Default, Debug)]
+/// Gains of the wheel speed controllers, from an error in ticks per second to a duty.
+const SPEED_PID: Pid = Pid::new(800.0, 3000.0, 0.0);
+/// Longest gap between two speed updates before the controllers start over, e.g. after a maneuver.
+const SPEED_CONTROL_GAP_US: u32 = 200_000;
+
+#[derive(Default, Debug, Clone)]
 pub struct SensorReadings {
     pub front_distance: Cm,
     pub left_distance: Cm,
+    pub right_distance: Cm,
+    pub front_status: DistanceStatus,
+    pub left_status: DistanceStatus,
+    pub right_status: DistanceStatus,
+    /// Calibrated from 0 (background) to 1000 (line), see `calibration::IrCalibration`.
     pub left_infrared: u16,
+    /// Calibrated from 0 (background) to 1000 (line), see `calibration::IrCalibration`.
     pub right_infrared: u16,
+    /// When the readings were taken, in microseconds of a clock which wraps around.
+    pub time_us: u32,
+}
+
+/// Provides fresh readings of all the sensors.
+pub trait SensorSource {
+    fn read(&mut self) -> SensorReadings;
+}
+
+/// Drives one wheel. Duties are fractions of full power out of `u16::MAX`, whatever the resolution of the PWM,
+/// and are limited to `get_max_duty`.
+pub trait MotorControl {
+    fn forward(&mut self, duty: u16);
+    fn backward(&mut self, duty: u16);
+    /// Coast to a stop.
+    fn stop(&mut self) {
+        self.forward(0);
+    }
+    /// Short the motor through the H-bridge, both inputs high, so it stops quicker than coasting.
+    fn brake(&mut self);
+    fn stop_with(&mut self, mode: StopMode) {
+        match mode {
+            StopMode::Coast => self.stop(),
+            StopMode::Brake => self.brake(),
+        }
+    }
+    /// The current duty and the direction it is applied in.
+    fn get_info(&self) -> (u16, Dir);
+    fn get_max_duty(&self) -> u16;
+    /// Drive forward for positive speeds and backward for negative ones, see `signed_duty`.
+    fn set_speed(&mut self, speed: i32) {
+        match signed_duty(speed) {
+            (duty, Dir::Fd) => self.forward(duty),
+            (duty, Dir::Bk) => self.backward(duty),
+        }
+    }
+    /// The current duty, negative when going backward.
+    fn get_speed(&self) -> i32 {
+        match self.get_info() {
+            (duty, Dir::Fd) => duty.into(),
+            (duty, Dir::Bk) => -i32::from(duty),
+        }
+    }
+}
+
+/// Counts the wheel encoder ticks and stops a motor after a number of them.
+pub trait Encoders {
+    /// Stop the left motor once its encoder counts another `ticks` ticks, or right away for 0.
+    fn lock_left_motor(&mut self, ticks: u32);
+    /// Stop the right motor once its encoder counts another `ticks` ticks, or right away for 0.
+    fn lock_right_motor(&mut self, ticks: u32);
+    /// Forget the locks of both motors, leaving them running.
+    fn unlock_motors(&mut self);
+    /// How the motors are stopped once their locks complete, coasting until changed.
+    fn set_lock_stop_mode(&mut self, mode: StopMode);
+    fn is_left_motor_locked(&self) -> bool;
+    fn is_right_motor_locked(&self) -> bool;
+    /// Total number of ticks counted by the left encoder.
+    fn left_ticks(&self) -> u32;
+    /// Total number of ticks counted by the right encoder.
+    fn right_ticks(&self) -> u32;
 }
 
+/// A motor driven by two TIM3 PWM channels. Duties are scaled to the auto-reload of the timer.
 pub struct Motor {
-    max_duty: *const u16,
+    arr: *const u16,
     fd_duty: *mut u16,
     bk_duty: *mut u16,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Dir {
     Fd,
     Bk,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StopMode {
+    /// Both H-bridge inputs low, the motor spins down freely.
+    #[default]
+    Coast,
+    /// Both H-bridge inputs high, the motor is shorted and stops within a few ticks.
+    Brake,
+}
+
+/// Split a signed speed into a duty and a direction, the duty clamped to `u16::MAX`. Zero is forward, stopped.
+pub fn signed_duty(speed: i32) -> (u16, Dir) {
+    let duty = speed.unsigned_abs().min(u16::MAX.into()) as u16;
+    if speed < 0 {
+        (duty, Dir::Bk)
+    } else {
+        (duty, Dir::Fd)
+    }
+}
+
 impl Motor {
-    fn new(max_duty: *const u16, fd_duty: *mut u16, bk_duty: *mut u16) -> Motor {
+    #[cfg(feature = "stm32")]
+    fn new(arr: *const u16, fd_duty: *mut u16, bk_duty: *mut u16) -> Motor {
         unsafe {
             ptr::write_volatile(fd_duty, 0);
             ptr::write_volatile(bk_duty, 0);
         }
         Self {
-            max_duty,
+            arr,
             fd_duty,
             bk_duty,
         }
     }
 
-    pub fn forward(&mut self, duty: u16) {
+    fn arr(&self) -> u32 {
+        unsafe { ptr::read_volatile(self.arr) }.into()
+    }
+}
+
+impl MotorControl for Motor {
+    fn forward(&mut self, duty: u16) {
+        let compare = duty_to_compare(duty, self.arr()) as u16;
         unsafe {
-            ptr::write_volatile(self.fd_duty, duty.min(ptr::read_volatile(self.max_duty)));
+            ptr::write_volatile(self.fd_duty, compare);
             ptr::write_volatile(self.bk_duty, 0);
         }
     }
 
-    pub fn backward(&mut self, duty: u16) {
+    fn backward(&mut self, duty: u16) {
+        let compare = duty_to_compare(duty, self.arr()) as u16;
         unsafe {
-            ptr::write_volatile(self.bk_duty, duty.min(ptr::read_volatile(self.max_duty)));
+            ptr::write_volatile(self.bk_duty, compare);
             ptr::write_volatile(self.fd_duty, 0);
         }
     }
 
-    pub fn stop(&mut self) {
-        self.forward(0);
+    fn brake(&mut self) {
+        // `configure_tim3` keeps ARR below u16::MAX, so ARR + 1 fits the CCRs
+        let compare = full_on_compare(self.arr()) as u16;
+        unsafe {
+            ptr::write_volatile(self.fd_duty, compare);
+            ptr::write_volatile(self.bk_duty, compare);
+        }
     }
 
-    pub fn get_info(&self) -> (u16, Dir) {
-        unsafe {
-            let fd_duty = ptr::read_volatile(self.fd_duty);
-            let bk_duty = ptr::read_volatile(self.bk_duty);
-            if bk_duty == 0 {
-                (fd_duty, Dir::Fd)
-            } else {
-                (bk_duty, Dir::Bk)
-            }
+    /// A braking motor is reported stopped.
+    fn get_info(&self) -> (u16, Dir) {
+        let (fd_duty, bk_duty) = unsafe {
+            (
+                ptr::read_volatile(self.fd_duty),
+                ptr::read_volatile(self.bk_duty),
+            )
+        };
+        if fd_duty != 0 && bk_duty != 0 {
+            (0, Dir::Fd)
+        } else if bk_duty == 0 {
+            (compare_to_duty(fd_duty.into(), self.arr()), Dir::Fd)
+        } else {
+            (compare_to_duty(bk_duty.into(), self.arr()), Dir::Bk)
         }
     }
 
-    pub fn get_max_duty(&self) -> u16 {
-        unsafe { ptr::read_volatile(self.max_duty) }
+    fn get_max_duty(&self) -> u16 {
+        u16::MAX
     }
 }
 
-pub struct Robot {
-    sensors: SensorReadings,
-    left_motor: Motor,
-    right_motor: Motor,
-}
+/// The encoders counted by TIM2 and TIM5, see `timers::configure_tim2`.
+/// Only implements `Encoders` with the `stm32` feature.
+pub struct EncoderTimers;
 
-impl Default for Robot {
-    fn default() -> Self {
-        Self {
-            left_motor: get_left_motor(),
-            right_motor: get_right_motor(),
-            sensors: Default::default(),
-        }
-    }
+pub struct Robot<M = Motor, E = EncoderTimers> {
+    sensors: SensorReadings,
+    left_motor: M,
+    right_motor: M,
+    encoders: E,
+    odometry: Odometry,
+    drive: DifferentialDrive,
+    /// When the speed controllers were last updated, see `drive_at_speed`.
+    last_speed_us: Option<u32>,
+    config: Config,
 }
 
-impl Robot {
-    pub fn new(left_motor: Motor, right_motor: Motor) -> Self {
+impl<M: MotorControl, E: Encoders> Robot<M, E> {
+    pub fn new(left_motor: M, right_motor: M, encoders: E) -> Self {
         Self {
             sensors: Default::default(),
             left_motor,
             right_motor,
+            encoders,
+            odometry: Odometry::new(&DEFAULT_DRIVE_CONFIG),
+            drive: DifferentialDrive::new(
+                DEFAULT_DRIVE_CONFIG,
+                SpeedController::new(SPEED_PID),
+                SpeedController::new(SPEED_PID),
+            ),
+            last_speed_us: None,
+            config: Default::default(),
         }
     }
 
@@ -105,53 +229,275 @@ impl Robot {
         self.sensors = sr;
     }
 
+    pub fn read_sensors(&mut self, source: &mut impl SensorSource) {
+        self.sensors = source.read();
+    }
+
     pub fn get_sensor_readings(&self) -> &SensorReadings {
         &self.sensors
     }
 
-    pub fn left_motor(&mut self) -> &mut Motor {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    pub fn left_motor(&mut self) -> &mut M {
         &mut self.left_motor
     }
 
-    pub fn right_motor(&mut self) -> &mut Motor {
+    pub fn right_motor(&mut self) -> &mut M {
         &mut self.right_motor
     }
 
+    pub fn encoders(&mut self) -> &mut E {
+        &mut self.encoders
+    }
+
     pub fn lock_left_motor(&mut self, ticks: u32) {
-        free(|cs| {
-            let some_tim2 = timers::G_TIM2.borrow(cs).borrow();
-            let tim2 = some_tim2.as_ref().unwrap();
-            tim2.cnt.reset();
-            tim2.arr.write(|w| w.arr().bits(ticks));
-            tim2.cr1.modify(|_, w| w.cen().enabled());
-        });
+        self.encoders.lock_left_motor(ticks);
     }
 
     pub fn lock_right_motor(&mut self, ticks: u32) {
-        free(|cs| {
-            let some_tim5 = timers::G_TIM5.borrow(cs).borrow();
-            let tim5 = some_tim5.as_ref().unwrap();
-            tim5.cnt.reset();
-            tim5.arr.write(|w| w.arr().bits(ticks));
-            tim5.cr1.modify(|_, w| w.cen().enabled());
-        });
+        self.encoders.lock_right_motor(ticks);
+    }
+
+    pub fn unlock_motors(&mut self) {
+        self.encoders.unlock_motors();
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.encoders.is_left_motor_locked() || self.encoders.is_right_motor_locked()
+    }
+
+    pub fn left_ticks(&self) -> u32 {
+        self.encoders.left_ticks()
+    }
+
+    pub fn right_ticks(&self) -> u32 {
+        self.encoders.right_ticks()
+    }
+
+    /// Integrate the ticks counted since the last call into the pose.
+    pub fn update_odometry(&mut self) {
+        let ticks = (self.left_ticks(), self.right_ticks());
+        let dirs = (self.left_motor.get_info().1, self.right_motor.get_info().1);
+        self.odometry.update(ticks, dirs);
+    }
+
+    pub fn get_pose(&self) -> Pose {
+        self.odometry.get_pose()
+    }
+
+    pub fn odometry(&mut self) -> &mut Odometry {
+        &mut self.odometry
+    }
+
+    /// Drive the wheels at `left` and `right` ticks per second, negative backward, measuring their speed
+    /// from the encoders so both keep the same pace whatever their motors do at a given duty.
+    /// Should be called after every sensor update for as long as the speeds are wanted.
+    pub fn drive_at_speed(&mut self, left: f32, right: f32) {
+        self.drive.left().set_target(left);
+        self.drive.right().set_target(right);
+        self.update_drive();
+    }
+
+    /// Like `drive_at_speed`, moving at `v` cm/s while turning at `omega` rad/s, counter-clockwise positive.
+    pub fn drive_at_velocity(&mut self, v: f32, omega: f32) {
+        self.drive.set_velocity(v, omega);
+        self.update_drive();
+    }
+
+    /// The speeds last measured by `drive_at_speed` or `drive_at_velocity`, in ticks per second.
+    pub fn get_wheel_speeds(&self) -> (f32, f32) {
+        self.drive.get_speeds()
+    }
+
+    fn update_drive(&mut self) {
+        let now = self.sensors.time_us;
+        let dt_us = self.last_speed_us.map(|last| now.wrapping_sub(last));
+        self.last_speed_us = Some(now);
+        let dt_us = match dt_us {
+            Some(dt_us) if dt_us <= SPEED_CONTROL_GAP_US => dt_us,
+            // The motors were driven by something else in between
+            _ => {
+                self.drive.reset();
+                0
+            }
+        };
+        let ticks = (self.left_ticks(), self.right_ticks());
+        self.drive.update(
+            &mut self.left_motor,
+            &mut self.right_motor,
+            ticks,
+            dt_us as f32 / 1e6,
+        );
     }
 }
 
-pub fn get_left_motor() -> Motor {
-    let tim3 = unsafe { &*TIM3::PTR };
-    Motor::new(
-        tim3.arr.as_ptr() as *const u16,
-        tim3.ccr3().as_ptr() as *mut u16,
-        tim3.ccr4().as_ptr() as *mut u16,
-    )
+#[cfg(feature = "stm32")]
+pub use hardware::{get_left_motor, get_right_motor, HardwareSensors};
+
+/// The STM32F401 implementations of the traits.
+#[cfg(feature = "stm32")]
+mod hardware {
+    use cortex_m::interrupt::free;
+    use stm32f4::stm32f401::TIM3;
+
+    use super::{EncoderTimers, Encoders, Motor, Robot, SensorReadings, SensorSource, StopMode};
+    use crate::calibration::IrCalibration;
+    use crate::distance::{FRONT, G_DISTANCES, LEFT, RIGHT};
+    use crate::{adc, clock, timers};
+
+    impl Default for Robot {
+        fn default() -> Self {
+            Self::new(get_left_motor(), get_right_motor(), EncoderTimers)
+        }
+    }
+
+    impl Encoders for EncoderTimers {
+        fn lock_left_motor(&mut self, ticks: u32) {
+            timers::lock_left_motor(ticks);
+        }
+
+        fn lock_right_motor(&mut self, ticks: u32) {
+            timers::lock_right_motor(ticks);
+        }
+
+        fn unlock_motors(&mut self) {
+            timers::unlock_motors();
+        }
+
+        fn set_lock_stop_mode(&mut self, mode: StopMode) {
+            timers::set_lock_stop_mode(mode);
+        }
+
+        fn is_left_motor_locked(&self) -> bool {
+            timers::is_left_motor_locked()
+        }
+
+        fn is_right_motor_locked(&self) -> bool {
+            timers::is_right_motor_locked()
+        }
+
+        fn left_ticks(&self) -> u32 {
+            timers::left_encoder_ticks()
+        }
+
+        fn right_ticks(&self) -> u32 {
+            timers::right_encoder_ticks()
+        }
+    }
+
+    /// Reads the infrared sensors through the ADC and the ultrasound sensors captured by TIM4.
+    pub struct HardwareSensors {
+        pub calibration: IrCalibration,
+    }
+
+    impl SensorSource for HardwareSensors {
+        fn read(&mut self) -> SensorReadings {
+            let [left_infrared, right_infrared] = self.calibration.normalize(adc::read_infrared());
+            let now = clock::micros();
+            let [front, left, right] = free(|cs| {
+                let distances = G_DISTANCES.borrow(cs).borrow();
+                [FRONT, LEFT, RIGHT].map(|sensor| {
+                    (
+                        distances[sensor].get_distance_cm(),
+                        distances[sensor].get_status(now),
+                    )
+                })
+            });
+            SensorReadings {
+                front_distance: front.0,
+                left_distance: left.0,
+                right_distance: right.0,
+                front_status: front.1,
+                left_status: left.1,
+                right_status: right.1,
+                left_infrared,
+                right_infrared,
+                time_us: now,
+            }
+        }
+    }
+
+    pub fn get_left_motor() -> Motor {
+        let tim3 = unsafe { &*TIM3::PTR };
+        Motor::new(
+            tim3.arr.as_ptr() as *const u16,
+            tim3.ccr3().as_ptr() as *mut u16,
+            tim3.ccr4().as_ptr() as *mut u16,
+        )
+    }
+
+    pub fn get_right_motor() -> Motor {
+        let tim3 = unsafe { &*TIM3::PTR };
+        Motor::new(
+            tim3.arr.as_ptr() as *const u16,
+            tim3.ccr1().as_ptr() as *mut u16,
+            tim3.ccr2().as_ptr() as *mut u16,
+        )
+    }
 }
 
-pub fn get_right_motor() -> Motor {
-    let tim3 = unsafe { &*TIM3::PTR };
-    Motor::new(
-        tim3.arr.as_ptr() as *const u16,
-        tim3.ccr1().as_ptr() as *mut u16,
-        tim3.ccr2().as_ptr() as *mut u16,
-    )
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::mock::{MockMotor, MockWheel};
+
+    #[test]
+    fn signed_duty_splits_direction() {
+        assert_eq!(signed_duty(0), (0, Dir::Fd));
+        assert_eq!(signed_duty(1), (1, Dir::Fd));
+        assert_eq!(signed_duty(-1), (1, Dir::Bk));
+        assert_eq!(signed_duty(65_535), (u16::MAX, Dir::Fd));
+        assert_eq!(signed_duty(-65_535), (u16::MAX, Dir::Bk));
+    }
+
+    #[test]
+    fn signed_duty_clamps() {
+        assert_eq!(signed_duty(70_000), (u16::MAX, Dir::Fd));
+        assert_eq!(signed_duty(-70_000), (u16::MAX, Dir::Bk));
+        assert_eq!(signed_duty(i32::MAX), (u16::MAX, Dir::Fd));
+        assert_eq!(signed_duty(i32::MIN), (u16::MAX, Dir::Bk));
+    }
+
+    #[test]
+    fn set_speed_in_both_directions() {
+        let wheel = RefCell::new(MockWheel::default());
+        let mut motor = MockMotor::new(&wheel);
+
+        motor.set_speed(30_000);
+        assert_eq!(
+            (wheel.borrow().fd_duty, wheel.borrow().bk_duty),
+            (30_000, 0)
+        );
+        assert_eq!(motor.get_speed(), 30_000);
+
+        motor.set_speed(-20_000);
+        assert_eq!(
+            (wheel.borrow().fd_duty, wheel.borrow().bk_duty),
+            (0, 20_000)
+        );
+        assert_eq!(motor.get_speed(), -20_000);
+
+        motor.set_speed(0);
+        assert_eq!(motor.get_info(), (0, Dir::Fd));
+    }
+
+    #[test]
+    fn set_speed_is_limited_to_the_max_duty() {
+        let wheel = RefCell::new(MockWheel::default());
+        let mut motor = MockMotor::new(&wheel);
+        motor.max_duty = 40_000;
+        motor.set_speed(-100_000);
+        assert_eq!(motor.get_speed(), -40_000);
+        motor.set_speed(50_000);
+        assert_eq!(motor.get_speed(), 40_000);
+    }
 }
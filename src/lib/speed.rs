@@ -0,0 +1,136 @@
+use crate::pid::Pid;
+use crate::robot::MotorControl;
+
+/// Shortest time the speed is measured over, as the few ticks of a single update would be too coarse.
+const SAMPLE_PERIOD_S: f32 = 0.1;
+
+/// Closed-loop speed control of a single wheel.
+/// The speed is measured from the encoder ticks and the PWM duty is adjusted to match the target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpeedController {
+    pid: Pid,
+    target: f32,
+    speed: f32,
+    duty: u16,
+    /// Ticks at the start of the current sample and the time since then in seconds.
+    sample: Option<(u32, f32)>,
+}
+
+impl SpeedController {
+    pub const fn new(pid: Pid) -> Self {
+        Self {
+            pid,
+            target: 0.0,
+            speed: 0.0,
+            duty: 0,
+            sample: None,
+        }
+    }
+
+    /// The target should be given in ticks per second.
+    /// Negative values make the wheel turn backward.
+    pub fn set_target(&mut self, target: f32) {
+        if target == 0.0 || target.is_sign_negative() != self.target.is_sign_negative() {
+            self.reset();
+        }
+        self.target = target;
+    }
+
+    pub fn get_target(&self) -> f32 {
+        self.target
+    }
+
+    /// The last measured speed in ticks per second, always positive.
+    pub fn get_speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn pid(&mut self) -> &mut Pid {
+        &mut self.pid
+    }
+
+    /// Start over from a standstill, e.g. after the motor was driven by something else.
+    pub fn reset(&mut self) {
+        self.pid.reset();
+        self.speed = 0.0;
+        self.sample = None;
+    }
+
+    /// Should be called periodically with the total encoder ticks and the time since the last call in seconds.
+    /// The duty is recomputed once per `SAMPLE_PERIOD_S`, and right away after a reset.
+    pub fn update(&mut self, motor: &mut impl MotorControl, ticks: u32, dt: f32) {
+        if self.target == 0.0 {
+            self.sample = None;
+            motor.stop();
+            return;
+        }
+
+        let step = match self.sample {
+            None => {
+                self.sample = Some((ticks, 0.0));
+                Some(0.0)
+            }
+            Some((start, elapsed)) if elapsed + dt >= SAMPLE_PERIOD_S => {
+                let elapsed = elapsed + dt;
+                self.speed = ticks.wrapping_sub(start) as f32 / elapsed;
+                self.sample = Some((ticks, 0.0));
+                Some(elapsed)
+            }
+            Some((start, elapsed)) => {
+                self.sample = Some((start, elapsed + dt));
+                None
+            }
+        };
+        if let Some(step) = step {
+            self.pid.set_limits(0.0, motor.get_max_duty() as f32);
+            self.duty = self.pid.update(self.target.abs() - self.speed, step) as u16;
+        }
+        if self.target > 0.0 {
+            motor.forward(self.duty);
+        } else {
+            motor.backward(self.duty);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::mock::{MockMotor, MockWheel};
+    use crate::robot::Dir;
+
+    #[test]
+    fn measures_the_tick_rate_across_the_counter_wrap() {
+        let wheel = RefCell::new(MockWheel::default());
+        let mut motor = MockMotor::new(&wheel);
+        let mut speed = SpeedController::new(Pid::new(100.0, 0.0, 0.0));
+        speed.set_target(40.0);
+
+        speed.update(&mut motor, u32::MAX - 1, 0.0);
+        assert_eq!(speed.get_speed(), 0.0);
+        assert_eq!(motor.get_info(), (4000, Dir::Fd));
+        speed.update(&mut motor, 0, 0.0625);
+        assert_eq!(motor.get_info(), (4000, Dir::Fd));
+        speed.update(&mut motor, 2, 0.0625);
+        assert_eq!(speed.get_speed(), 32.0);
+        assert_eq!(motor.get_info(), (800, Dir::Fd));
+    }
+
+    #[test]
+    fn keeps_the_duty_between_samples() {
+        let wheel = RefCell::new(MockWheel::default());
+        let mut motor = MockMotor::new(&wheel);
+        let mut speed = SpeedController::new(Pid::new(100.0, 0.0, 0.0));
+        speed.set_target(-30.0);
+        speed.update(&mut motor, 0, 0.0);
+        speed.update(&mut motor, 1, 0.05);
+        assert_eq!(speed.get_speed(), 0.0);
+        assert_eq!(motor.get_info(), (3000, Dir::Bk));
+
+        speed.set_target(0.0);
+        speed.update(&mut motor, 1, 0.05);
+        assert_eq!(motor.get_info(), (0, Dir::Fd));
+    }
+}
@@ -0,0 +1,50 @@
+use crate::drive::DEFAULT_DRIVE_CONFIG;
+use crate::robot::{Encoders, MotorControl, Robot};
+
+/// Fastest the inner wheel gets as a fraction of the outer one, so the spiral never becomes a straight line.
+const MAX_INNER_RATIO: f32 = 0.9;
+
+/// Searches for a lost line by driving an anticlockwise spiral, starting on the spot and widening with time.
+/// Time is taken from `SensorReadings::time_us`, starting at the first update.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpiralSearch {
+    /// Speed of the outer wheel in cm/s.
+    outer_speed: f32,
+    growth: f32,
+    started_us: Option<u32>,
+}
+
+impl Default for SpiralSearch {
+    fn default() -> Self {
+        Self::new(25.0, 0.1)
+    }
+}
+
+impl SpiralSearch {
+    /// `growth` is how much the inner wheel speeds up per second, as a fraction of the outer wheel.
+    pub const fn new(outer_speed: f32, growth: f32) -> Self {
+        Self {
+            outer_speed,
+            growth,
+            started_us: None,
+        }
+    }
+
+    /// Drive along the spiral. Returns true, leaving the motors running, once either infrared sensor sees the line.
+    pub fn update<M: MotorControl, E: Encoders>(&mut self, robot: &mut Robot<M, E>) -> bool {
+        let sr = robot.get_sensor_readings();
+        let on_line = robot.config().ir_on_line;
+        if sr.left_infrared > on_line || sr.right_infrared > on_line {
+            return true;
+        }
+        let now = sr.time_us;
+        let started = *self.started_us.get_or_insert(now);
+        let elapsed_s = now.wrapping_sub(started) as f32 / 1e6;
+        let ratio = (self.growth * elapsed_s).min(MAX_INNER_RATIO);
+        // The right wheel is on the outside, the turn widens as the left one catches up
+        let v = self.outer_speed * (1.0 + ratio) / 2.0;
+        let omega = self.outer_speed * (1.0 - ratio) / DEFAULT_DRIVE_CONFIG.track_width_cm;
+        robot.drive_at_velocity(v, omega);
+        false
+    }
+}
@@ -1,22 +1,81 @@
-use super::robot::Robot;
-use cortex_m::asm;
+use core::mem::discriminant;
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+use super::distance::DistanceStatus;
+use super::line::LineFollower;
+use super::motion::{Motion, MotionOutcome};
+use super::robot::{Encoders, MotorControl, Robot, SensorReadings};
+use super::spiral::SpiralSearch;
+use super::wall::WallFollower;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum State {
     FollowingLine,
+    /// Alternative to `FollowingLine` steering proportionally to the line position.
+    PidFollowingLine(LineFollower),
     FollowingLineAndAvoiding,
     Forward,
     TurningLeft,
     TurningRight,
     ReturnToLine,
     Avoiding,
+    /// Keeps a distance to the wall on the left, following it around corners.
+    FollowingWall(WallFollower),
+    /// Runs a maneuver of a number of encoder ticks while the sensors keep being read.
+    Maneuvering(Maneuver, Motion),
+    /// Recovers from getting stuck by spiralling out until the line is found.
+    SpiralSearch(SpiralSearch),
     Stopped,
 }
 
+/// Longest any maneuver may take before the encoders are assumed to be broken.
+const MANEUVER_TIMEOUT_MS: u32 = 3_000;
+/// Speed of both wheels when driving straight, in ticks per second.
+const STRAIGHT_SPEED: f32 = 28.0;
+
+/// The maneuvers of the obstacle avoidance, run by `State::Maneuvering`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Maneuver {
+    /// Turn away from the obstacle in front.
+    TurnRight,
+    /// Turn back towards the line once past the obstacle.
+    TurnLeft,
+    /// Back off from the line after running into it.
+    BackOff,
+    /// Drive on until the obstacle is behind.
+    PassObstacle,
+    /// Drive onto the line once the left sensor is over it.
+    CentreOnLine,
+    /// Turn a little further towards the line.
+    SearchLine,
+}
+
+impl Maneuver {
+    /// Where to go once the maneuver completed.
+    fn next_state(self) -> State {
+        match self {
+            Maneuver::TurnRight => State::Avoiding,
+            Maneuver::TurnLeft => State::Forward,
+            Maneuver::BackOff => State::ReturnToLine,
+            Maneuver::PassObstacle => State::TurningLeft,
+            Maneuver::CentreOnLine => State::FollowingLineAndAvoiding,
+            Maneuver::SearchLine => State::ReturnToLine,
+        }
+    }
+
+    /// A sensor condition cancelling the maneuver, with the state to go to instead.
+    fn interrupted_by(self, sr: &SensorReadings) -> Option<State> {
+        match self {
+            Maneuver::PassObstacle if is_front_blocked(sr) => Some(State::TurningRight),
+            _ => None,
+        }
+    }
+}
+
 impl State {
-    pub fn process_state(self, robot: &mut Robot) -> Self {
+    pub fn process_state<M: MotorControl, E: Encoders>(self, robot: &mut Robot<M, E>) -> Self {
         match self {
             State::FollowingLine => following_line(robot),
+            State::PidFollowingLine(follower) => pid_following_line(robot, follower),
             State::Stopped => State::Stopped,
             State::FollowingLineAndAvoiding => following_line_and_avoiding(robot),
             State::TurningRight => turning_right(robot),
@@ -24,14 +83,115 @@ impl State {
             State::Avoiding => avoiding(robot),
             State::Forward => forward(robot),
             Self::ReturnToLine => return_to_line(robot),
+            State::FollowingWall(follower) => following_wall(robot, follower),
+            State::Maneuvering(maneuver, motion) => maneuvering(robot, maneuver, motion),
+            State::SpiralSearch(search) => spiral_search(robot, search),
         }
     }
+
+    /// Longest the state should last, after which the robot is considered stuck. `None` if it may last forever.
+    pub fn max_dwell_ms(&self) -> Option<u32> {
+        match self {
+            State::Forward => Some(10_000),
+            State::ReturnToLine => Some(8_000),
+            State::Avoiding => Some(15_000),
+            State::SpiralSearch(_) => Some(30_000),
+            _ => None,
+        }
+    }
+
+    /// Where to go once the state lasted longer than `max_dwell_ms`.
+    pub fn fallback(&self) -> State {
+        match self {
+            State::Forward | State::ReturnToLine | State::Avoiding => {
+                State::SpiralSearch(SpiralSearch::default())
+            }
+            _ => State::Stopped,
+        }
+    }
+}
+
+/// Runs the states, tracking how long each of them lasts and falling back when one lasts too long.
+/// Maneuvers count towards the state which started them, so a state looping through maneuvers still times out.
+/// Time is taken from `SensorReadings::time_us`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StateMachine {
+    state: State,
+    /// The last state which is not a maneuver.
+    dwelling: State,
+    entered_us: Option<u32>,
 }
 
-fn following_line(robot: &mut Robot) -> State {
+impl StateMachine {
+    pub const fn new(state: State) -> Self {
+        Self {
+            state,
+            dwelling: state,
+            entered_us: None,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Switch to another state, e.g. on a command, restarting the dwell time.
+    /// The new state starts from a standstill, whatever the old one or its maneuver was doing.
+    pub fn set_state<M: MotorControl, E: Encoders>(
+        &mut self,
+        robot: &mut Robot<M, E>,
+        state: State,
+    ) {
+        halt(robot);
+        *self = Self::new(state);
+    }
+
+    /// How long the current state lasted, as of the last sensor readings.
+    pub fn elapsed_ms<M: MotorControl, E: Encoders>(&self, robot: &Robot<M, E>) -> u32 {
+        match self.entered_us {
+            Some(entered) => robot.get_sensor_readings().time_us.wrapping_sub(entered) / 1000,
+            None => 0,
+        }
+    }
+
+    /// Process the current state, or its fallback if it lasted too long. Returns the new state.
+    pub fn process<M: MotorControl, E: Encoders>(&mut self, robot: &mut Robot<M, E>) -> State {
+        let now = robot.get_sensor_readings().time_us;
+        let entered = *self.entered_us.get_or_insert(now);
+        let stuck = self
+            .dwelling
+            .max_dwell_ms()
+            .is_some_and(|max| now.wrapping_sub(entered) / 1000 > max);
+        let next = if stuck {
+            // The fallback starts from a standstill, whatever the stuck state was doing
+            halt(robot);
+            self.dwelling.fallback()
+        } else {
+            self.state.process_state(robot)
+        };
+
+        self.state = next;
+        let is_maneuver = matches!(next, State::Maneuvering(..));
+        if !is_maneuver && discriminant(&next) != discriminant(&self.dwelling) {
+            self.dwelling = next;
+            self.entered_us = Some(now);
+        }
+        next
+    }
+}
+
+/// Stop both motors and drop the locks of a maneuver, so they can not restart or stop them later.
+fn halt<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>) {
+    robot.unlock_motors();
+    robot.left_motor().stop();
+    robot.right_motor().stop();
+}
+
+fn following_line<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>) -> State {
     let readings = robot.get_sensor_readings();
-    let left_is_black = readings.left_infrared > 600;
-    let right_is_black = readings.right_infrared > 600;
+    let on_line = robot.config().ir_on_line;
+    let left_is_black = readings.left_infrared > on_line;
+    let right_is_black = readings.right_infrared > on_line;
     match (left_is_black, right_is_black) {
         (true, false) => {
             robot.left_motor().backward(u16::MAX);
@@ -49,8 +209,41 @@ fn following_line(robot: &mut Robot) -> State {
     State::FollowingLine
 }
 
-fn following_line_and_avoiding(robot: &mut Robot) -> State {
-    if robot.get_sensor_readings().front_distance > 12 {
+fn pid_following_line<M: MotorControl, E: Encoders>(
+    robot: &mut Robot<M, E>,
+    mut follower: LineFollower,
+) -> State {
+    follower.update(robot);
+    State::PidFollowingLine(follower)
+}
+
+fn following_wall<M: MotorControl, E: Encoders>(
+    robot: &mut Robot<M, E>,
+    mut follower: WallFollower,
+) -> State {
+    follower.update(robot);
+    State::FollowingWall(follower)
+}
+
+fn spiral_search<M: MotorControl, E: Encoders>(
+    robot: &mut Robot<M, E>,
+    mut search: SpiralSearch,
+) -> State {
+    if search.update(robot) {
+        State::FollowingLineAndAvoiding
+    } else {
+        State::SpiralSearch(search)
+    }
+}
+
+fn following_line_and_avoiding<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>) -> State {
+    let sr = robot.get_sensor_readings();
+    if !sr.front_status.is_known() {
+        // Obstacles cannot be seen, wait for the sensor to recover
+        robot.left_motor().stop();
+        robot.right_motor().stop();
+        State::FollowingLineAndAvoiding
+    } else if !is_front_blocked(sr) {
         following_line(robot);
         State::FollowingLineAndAvoiding
     } else {
@@ -58,85 +251,359 @@ fn following_line_and_avoiding(robot: &mut Robot) -> State {
     }
 }
 
-fn turning_right(robot: &mut Robot) -> State {
+fn turning_right<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>) -> State {
     robot.left_motor().forward(u16::MAX);
     robot.right_motor().stop();
-    robot.lock_left_motor(17);
-
-    wait_till_stopped(robot);
-
-    State::Avoiding
+    let ticks = robot.config().turn_right_ticks;
+    start_maneuver(robot, Maneuver::TurnRight, Some(ticks), None)
 }
 
-fn turning_left(robot: &mut Robot) -> State {
+fn turning_left<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>) -> State {
     robot.left_motor().backward(50_000);
     robot.right_motor().forward(40_000);
-    robot.lock_left_motor(8);
-    robot.lock_right_motor(9);
-
-    wait_till_stopped(robot);
-
-    State::Forward
+    let [left_ticks, right_ticks] = robot.config().turn_left_ticks;
+    start_maneuver(
+        robot,
+        Maneuver::TurnLeft,
+        Some(left_ticks),
+        Some(right_ticks),
+    )
 }
 
-fn avoiding(robot: &mut Robot) -> State {
+fn avoiding<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>) -> State {
     let sr = robot.get_sensor_readings();
-    if sr.left_infrared > 300 || sr.right_infrared > 300 {
+    let config = *robot.config();
+    if !sr.left_status.is_known() || !sr.front_status.is_known() {
+        robot.left_motor().stop();
+        robot.right_motor().stop();
+        State::Avoiding
+    } else if sr.left_infrared > config.ir_touching_line
+        || sr.right_infrared > config.ir_touching_line
+    {
         robot.left_motor().backward(55_000);
         robot.right_motor().backward(48_000);
-        robot.lock_left_motor(4);
-        robot.lock_right_motor(4);
-        wait_till_stopped(robot);
-        State::ReturnToLine
-    } else if sr.left_distance > 50 {
+        let ticks = Some(config.back_off_ticks);
+        start_maneuver(robot, Maneuver::BackOff, ticks, ticks)
+    } else if is_left_clear(sr, 50) {
         robot.left_motor().forward(56_000);
         robot.right_motor().forward(46_000);
-        robot.lock_left_motor(8);
-        robot.lock_right_motor(8);
-        wait_till_stopped(robot);
-        State::TurningLeft
-    } else if sr.front_distance < 12 {
+        let ticks = Some(config.pass_obstacle_ticks);
+        start_maneuver(robot, Maneuver::PassObstacle, ticks, ticks)
+    } else if is_front_blocked(sr) {
         State::TurningRight
     } else {
-        robot.left_motor().forward(55_000);
-        robot.right_motor().forward(48_000);
+        robot.drive_at_speed(STRAIGHT_SPEED, STRAIGHT_SPEED);
         State::Avoiding
     }
 }
 
-fn return_to_line(robot: &mut Robot) -> State {
-    if robot.get_sensor_readings().left_infrared > 800 {
+fn return_to_line<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>) -> State {
+    let config = *robot.config();
+    if robot.get_sensor_readings().left_infrared > config.ir_centred_on_line {
         robot.left_motor().forward(50_000);
-        robot.lock_left_motor(4);
-        wait_till_stopped(robot);
-        return State::FollowingLineAndAvoiding;
+        let ticks = Some(config.centre_on_line_ticks);
+        return start_maneuver(robot, Maneuver::CentreOnLine, ticks, None);
     }
     robot.left_motor().forward(45_000);
-    robot.lock_left_motor(2);
-    wait_till_stopped(robot);
-    State::ReturnToLine
+    let ticks = Some(config.search_line_ticks);
+    start_maneuver(robot, Maneuver::SearchLine, ticks, None)
 }
 
-fn forward(robot: &mut Robot) -> State {
-    robot.left_motor().forward(55_000);
-    robot.right_motor().forward(46_000);
+fn forward<M: MotorControl, E: Encoders>(robot: &mut Robot<M, E>) -> State {
+    robot.drive_at_speed(STRAIGHT_SPEED, STRAIGHT_SPEED);
     let sr = robot.get_sensor_readings();
-    if sr.left_infrared > 300 || sr.right_infrared > 300 {
+    let config = *robot.config();
+    if sr.left_infrared > config.ir_touching_line || sr.right_infrared > config.ir_touching_line {
         robot.left_motor().backward(55_000);
         robot.right_motor().backward(46_000);
-        robot.lock_left_motor(4);
-        robot.lock_right_motor(4);
-        wait_till_stopped(robot);
-        State::ReturnToLine
-    } else if sr.left_distance > 30 {
+        let ticks = Some(config.back_off_ticks);
+        start_maneuver(robot, Maneuver::BackOff, ticks, ticks)
+    } else if !sr.left_status.is_known() {
+        robot.left_motor().stop();
+        robot.right_motor().stop();
+        State::Forward
+    } else if is_left_clear(sr, 30) {
         State::Forward
     } else {
         State::Avoiding
     }
 }
 
-fn wait_till_stopped(robot: &mut Robot) {
-    while robot.left_motor().get_info().0 != 0 || robot.right_motor().get_info().0 != 0 {
-        asm::nop();
+/// Out of range means nothing is in front, too close that the obstacle is nearer than any distance.
+fn is_front_blocked(sr: &SensorReadings) -> bool {
+    match sr.front_status {
+        DistanceStatus::Valid => sr.front_distance < 12,
+        DistanceStatus::TooClose => true,
+        _ => false,
+    }
+}
+
+fn is_left_clear(sr: &SensorReadings, distance: u16) -> bool {
+    match sr.left_status {
+        DistanceStatus::OutOfRange => true,
+        DistanceStatus::TooClose => false,
+        _ => sr.left_distance > distance,
+    }
+}
+
+/// Lock the wheels given a number of ticks, the motors should already be driven.
+fn start_maneuver<M: MotorControl, E: Encoders>(
+    robot: &mut Robot<M, E>,
+    maneuver: Maneuver,
+    left_ticks: Option<u16>,
+    right_ticks: Option<u16>,
+) -> State {
+    let motion = Motion::start(
+        robot,
+        left_ticks.map(u32::from),
+        right_ticks.map(u32::from),
+        MANEUVER_TIMEOUT_MS,
+    );
+    State::Maneuvering(maneuver, motion)
+}
+
+fn maneuvering<M: MotorControl, E: Encoders>(
+    robot: &mut Robot<M, E>,
+    maneuver: Maneuver,
+    motion: Motion,
+) -> State {
+    if let Some(state) = maneuver.interrupted_by(robot.get_sensor_readings()) {
+        motion.cancel(robot);
+        return state;
+    }
+    match motion.poll(robot) {
+        None => State::Maneuvering(maneuver, motion),
+        Some(MotionOutcome::Completed) => maneuver.next_state(),
+        // Carrying on without working encoders would only get the robot lost
+        Some(MotionOutcome::TimedOut | MotionOutcome::Cancelled) => State::Stopped,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::mock::{MockEncoders, MockMotor, MockWheel};
+    use crate::robot::{Dir, SensorReadings};
+
+    type MockRobot<'a> = Robot<MockMotor<'a>, MockEncoders<'a>>;
+
+    fn mock_robot<'a>(
+        left: &'a RefCell<MockWheel>,
+        right: &'a RefCell<MockWheel>,
+    ) -> MockRobot<'a> {
+        Robot::new(
+            MockMotor::new(left),
+            MockMotor::new(right),
+            MockEncoders { left, right },
+        )
+    }
+
+    /// Nothing around and no line under the sensors.
+    fn clear_readings() -> SensorReadings {
+        SensorReadings {
+            front_distance: 400,
+            left_distance: 400,
+            front_status: DistanceStatus::Valid,
+            left_status: DistanceStatus::Valid,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn follows_the_line_by_reversing_the_inner_wheel() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        let mut sr = clear_readings();
+        sr.left_infrared = 900;
+        robot.update_sensors(sr);
+
+        assert_eq!(
+            State::FollowingLine.process_state(&mut robot),
+            State::FollowingLine
+        );
+        assert_eq!(robot.left_motor().get_info(), (u16::MAX, Dir::Bk));
+        assert_eq!(robot.right_motor().get_info(), (45_000, Dir::Fd));
+    }
+
+    /// Keep processing while a maneuver runs and the wheels turn, returning the state it ends in.
+    fn finish_maneuver(mut state: State, robot: &mut MockRobot) -> State {
+        for _ in 0..100 {
+            robot.encoders().step();
+            state = state.process_state(robot);
+            if !matches!(state, State::Maneuvering(..)) {
+                return state;
+            }
+        }
+        panic!("maneuver did not complete");
+    }
+
+    #[test]
+    fn obstacle_in_front_turns_to_avoid_it() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        let mut sr = clear_readings();
+        sr.front_distance = 10;
+        robot.update_sensors(sr);
+
+        let state = State::FollowingLineAndAvoiding.process_state(&mut robot);
+        assert_eq!(state, State::TurningRight);
+        let state = state.process_state(&mut robot);
+        assert!(matches!(state, State::Maneuvering(Maneuver::TurnRight, _)));
+        assert_eq!(left.borrow().bk_duty, 0);
+        assert!(left.borrow().fd_duty > 0);
+
+        assert_eq!(finish_maneuver(state, &mut robot), State::Avoiding);
+        let turn_ticks = robot.config().turn_right_ticks as u32;
+        assert_eq!(left.borrow().ticks, turn_ticks);
+        assert_eq!(robot.left_motor().get_info(), (0, Dir::Fd));
+    }
+
+    #[test]
+    fn reaching_the_line_backs_off_and_returns_to_it() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        let mut sr = clear_readings();
+        sr.left_distance = 20;
+        sr.right_infrared = 700;
+        robot.update_sensors(sr);
+
+        let state = State::Avoiding.process_state(&mut robot);
+        assert!(matches!(state, State::Maneuvering(Maneuver::BackOff, _)));
+        assert_eq!(robot.left_motor().get_info().1, Dir::Bk);
+        assert_eq!(finish_maneuver(state, &mut robot), State::ReturnToLine);
+    }
+
+    #[test]
+    fn lost_line_is_searched_for_until_centred_on() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        robot.update_sensors(clear_readings());
+
+        let state = State::ReturnToLine.process_state(&mut robot);
+        assert!(matches!(state, State::Maneuvering(Maneuver::SearchLine, _)));
+        assert_eq!(finish_maneuver(state, &mut robot), State::ReturnToLine);
+
+        let mut sr = clear_readings();
+        sr.left_infrared = 900;
+        robot.update_sensors(sr);
+        let state = State::ReturnToLine.process_state(&mut robot);
+        assert!(matches!(
+            state,
+            State::Maneuvering(Maneuver::CentreOnLine, _)
+        ));
+        assert_eq!(
+            finish_maneuver(state, &mut robot),
+            State::FollowingLineAndAvoiding
+        );
+    }
+
+    #[test]
+    fn lasting_too_long_falls_back() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        let mut sr = clear_readings();
+        robot.update_sensors(sr.clone());
+
+        let mut machine = StateMachine::new(State::Forward);
+        assert_eq!(machine.process(&mut robot), State::Forward);
+        assert!(robot.left_motor().get_info().0 > 0);
+
+        sr.time_us = 10_000_000;
+        robot.update_sensors(sr.clone());
+        assert_eq!(machine.process(&mut robot), State::Forward);
+        assert_eq!(machine.elapsed_ms(&robot), 10_000);
+
+        sr.time_us = 10_001_000;
+        robot.update_sensors(sr);
+        let state = machine.process(&mut robot);
+        assert_eq!(state, State::SpiralSearch(SpiralSearch::default()));
+        assert_eq!(robot.left_motor().get_info(), (0, Dir::Fd));
+        assert_eq!(robot.right_motor().get_info(), (0, Dir::Fd));
+        assert_eq!(machine.elapsed_ms(&robot), 0);
+    }
+
+    #[test]
+    fn states_without_a_limit_never_fall_back() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        let mut sr = clear_readings();
+        robot.update_sensors(sr.clone());
+
+        let mut machine = StateMachine::new(State::FollowingLine);
+        machine.process(&mut robot);
+        sr.time_us = 100_000_000;
+        robot.update_sensors(sr);
+        assert_eq!(machine.process(&mut robot), State::FollowingLine);
+    }
+
+    #[test]
+    fn forced_states_start_from_a_standstill() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        let mut sr = clear_readings();
+        sr.front_status = DistanceStatus::Valid;
+        sr.front_distance = 10;
+        robot.update_sensors(sr);
+
+        let mut machine = StateMachine::new(State::FollowingLineAndAvoiding);
+        machine.process(&mut robot);
+        let state = machine.process(&mut robot);
+        assert!(matches!(state, State::Maneuvering(Maneuver::TurnRight, _)));
+        assert!(left.borrow().lock.is_some());
+
+        machine.set_state(&mut robot, State::Stopped);
+        assert_eq!(machine.state(), State::Stopped);
+        assert_eq!(*left.borrow(), MockWheel::default());
+        assert_eq!(*right.borrow(), MockWheel::default());
+        assert_eq!(machine.process(&mut robot), State::Stopped);
+
+        robot.left_motor().forward(30_000);
+        robot.lock_left_motor(5);
+        machine.set_state(&mut robot, State::Avoiding);
+        assert_eq!(machine.state(), State::Avoiding);
+        assert_eq!(left.borrow().fd_duty, 0);
+        assert_eq!(left.borrow().lock, None);
+    }
+
+    #[test]
+    fn too_close_echoes_block() {
+        let mut sr = clear_readings();
+        assert!(!is_front_blocked(&sr));
+        assert!(is_left_clear(&sr, 50));
+
+        // The last distance measured says nothing about an echo too short to measure
+        sr.front_status = DistanceStatus::TooClose;
+        sr.left_status = DistanceStatus::TooClose;
+        sr.front_distance = 400;
+        sr.left_distance = 400;
+        assert!(is_front_blocked(&sr));
+        assert!(!is_left_clear(&sr, 50));
+
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        robot.update_sensors(sr);
+        assert_eq!(
+            State::FollowingLineAndAvoiding.process_state(&mut robot),
+            State::TurningRight
+        );
+    }
+
+    #[test]
+    fn stopped_stays_stopped() {
+        let (left, right) = (RefCell::default(), RefCell::default());
+        let mut robot = mock_robot(&left, &right);
+        robot.update_sensors(clear_readings());
+        assert_eq!(State::Stopped.process_state(&mut robot), State::Stopped);
+        assert_eq!(robot.left_motor().get_info(), (0, Dir::Fd));
+        assert_eq!(
+            State::Forward.fallback(),
+            State::SpiralSearch(SpiralSearch::default())
+        );
+        assert_eq!(
+            State::SpiralSearch(SpiralSearch::default()).fallback(),
+            State::Stopped
+        );
     }
 }
@@ -0,0 +1,216 @@
+//! Prescaler (PSC) and auto-reload (ARR) values for a timer from the frequencies wanted of it.
+//! This module does not touch any hardware.
+
+/// Largest auto-reload of the 16 bit timers, all but TIM2 and TIM5.
+pub const MAX_ARR_16: u32 = u16::MAX as u32;
+
+/// Values to write to a timer's PSC and ARR registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerSettings {
+    pub psc: u16,
+    pub arr: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// The frequency is zero or above the timer clock, or the period is shorter than two ticks.
+    TooFast,
+    /// The prescaler or the auto-reload would not fit in their registers.
+    TooSlow,
+}
+
+/// Prescaler making a timer running at `timer_hz` count at `tick_hz`, rounded to the nearest.
+pub fn prescaler(timer_hz: u32, tick_hz: u32) -> Result<u16, TimerError> {
+    if tick_hz == 0 || tick_hz > timer_hz {
+        return Err(TimerError::TooFast);
+    }
+    let div = (timer_hz + tick_hz / 2) / tick_hz;
+    u16::try_from(div - 1).map_err(|_| TimerError::TooSlow)
+}
+
+/// Auto-reload making a timer counting at `tick_hz` overflow every `period_ms`.
+pub fn reload_for_ms(tick_hz: u32, period_ms: u32, max_arr: u32) -> Result<u32, TimerError> {
+    let ticks = tick_hz as u64 * period_ms as u64 / 1000;
+    reload(ticks, max_arr)
+}
+
+fn reload(period_ticks: u64, max_arr: u32) -> Result<u32, TimerError> {
+    if period_ticks < 2 {
+        Err(TimerError::TooFast)
+    } else if period_ticks - 1 > max_arr as u64 {
+        Err(TimerError::TooSlow)
+    } else {
+        Ok((period_ticks - 1) as u32)
+    }
+}
+
+/// Count at `tick_hz` and overflow every `period_ticks` ticks.
+pub fn periodic(
+    timer_hz: u32,
+    tick_hz: u32,
+    period_ticks: u32,
+    max_arr: u32,
+) -> Result<TimerSettings, TimerError> {
+    Ok(TimerSettings {
+        psc: prescaler(timer_hz, tick_hz)?,
+        arr: reload(period_ticks.into(), max_arr)?,
+    })
+}
+
+/// Overflow at `freq_hz` with the finest resolution: the smallest prescaler whose auto-reload fits in `max_arr`.
+pub fn pwm(timer_hz: u32, freq_hz: u32, max_arr: u32) -> Result<TimerSettings, TimerError> {
+    if freq_hz == 0 || freq_hz > timer_hz {
+        return Err(TimerError::TooFast);
+    }
+    let period_ticks = timer_hz as u64 / freq_hz as u64;
+    let div = period_ticks.div_ceil(max_arr as u64 + 1);
+    let psc = u16::try_from(div - 1).map_err(|_| TimerError::TooSlow)?;
+    let div_hz = div * freq_hz as u64;
+    let arr = reload((timer_hz as u64 + div_hz / 2) / div_hz, max_arr)?;
+    Ok(TimerSettings { psc, arr })
+}
+
+/// Compare value giving `duty` out of `u16::MAX` with a timer reloading at `arr`.
+pub fn duty_to_compare(duty: u16, arr: u32) -> u32 {
+    ((duty as u64 * arr as u64 + u16::MAX as u64 / 2) / u16::MAX as u64) as u32
+}
+
+/// Compare value keeping a PWM mode 1 output high all the time with a timer reloading at `arr`.
+pub fn full_on_compare(arr: u32) -> u32 {
+    arr.saturating_add(1)
+}
+
+/// The duty out of `u16::MAX` a compare value gives with a timer reloading at `arr`.
+pub fn compare_to_duty(compare: u32, arr: u32) -> u16 {
+    if arr == 0 {
+        return 0;
+    }
+    let duty = (compare as u64 * u16::MAX as u64 + arr as u64 / 2) / arr as u64;
+    duty.min(u16::MAX as u64) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prescaler_boundaries() {
+        assert_eq!(prescaler(16_000_000, 0), Err(TimerError::TooFast));
+        assert_eq!(prescaler(16_000_000, 16_000_001), Err(TimerError::TooFast));
+        assert_eq!(prescaler(16_000_000, 16_000_000), Ok(0));
+        assert_eq!(prescaler(65_536_000, 1000), Ok(u16::MAX));
+        assert_eq!(prescaler(65_537_000, 1000), Err(TimerError::TooSlow));
+    }
+
+    #[test]
+    fn reload_boundaries() {
+        assert_eq!(
+            periodic(16_000_000, 1_000_000, 1, MAX_ARR_16),
+            Err(TimerError::TooFast)
+        );
+        assert_eq!(
+            periodic(16_000_000, 1_000_000, 2, MAX_ARR_16),
+            Ok(TimerSettings { psc: 15, arr: 1 })
+        );
+        assert_eq!(
+            periodic(16_000_000, 1_000_000, MAX_ARR_16 + 1, MAX_ARR_16),
+            Ok(TimerSettings {
+                psc: 15,
+                arr: MAX_ARR_16
+            })
+        );
+        assert_eq!(
+            periodic(16_000_000, 1_000_000, MAX_ARR_16 + 2, MAX_ARR_16),
+            Err(TimerError::TooSlow)
+        );
+        assert_eq!(
+            reload_for_ms(100_000, 0, MAX_ARR_16),
+            Err(TimerError::TooFast)
+        );
+        assert_eq!(reload_for_ms(100_000, 655, MAX_ARR_16), Ok(65_499));
+        assert_eq!(
+            reload_for_ms(100_000, 656, MAX_ARR_16),
+            Err(TimerError::TooSlow)
+        );
+    }
+
+    #[test]
+    fn trigger_period_of_40ms() {
+        assert_eq!(prescaler(16_000_000, 100_000), Ok(159));
+        assert_eq!(prescaler(84_000_000, 100_000), Ok(839));
+        assert_eq!(reload_for_ms(100_000, 40, MAX_ARR_16), Ok(3999));
+    }
+
+    #[test]
+    fn pwm_at_20khz() {
+        assert_eq!(
+            pwm(16_000_000, 20_000, MAX_ARR_16),
+            Ok(TimerSettings { psc: 0, arr: 799 })
+        );
+        assert_eq!(
+            pwm(84_000_000, 20_000, MAX_ARR_16),
+            Ok(TimerSettings { psc: 0, arr: 4199 })
+        );
+    }
+
+    #[test]
+    fn pwm_prescales_slow_frequencies() {
+        assert_eq!(
+            pwm(84_000_000, 1000, MAX_ARR_16),
+            Ok(TimerSettings {
+                psc: 1,
+                arr: 41_999
+            })
+        );
+        assert_eq!(
+            pwm(16_000_000, 1, MAX_ARR_16),
+            Ok(TimerSettings {
+                psc: 244,
+                arr: 65_305
+            })
+        );
+    }
+
+    #[test]
+    fn compares_round_trip_through_duties() {
+        for arr in [4199, MAX_ARR_16] {
+            assert_eq!(duty_to_compare(0, arr), 0);
+            assert_eq!(duty_to_compare(u16::MAX, arr), arr);
+            for compare in 0..=arr {
+                assert_eq!(duty_to_compare(compare_to_duty(compare, arr), arr), compare);
+            }
+        }
+    }
+
+    #[test]
+    fn duties_round_trip_within_a_step() {
+        // Half of a compare step, in duty
+        let max_error = u16::MAX as u32 / (2 * 4199) + 1;
+        for duty in 0..=u16::MAX {
+            let back = compare_to_duty(duty_to_compare(duty, 4199), 4199);
+            assert!((back as u32).abs_diff(duty as u32) <= max_error);
+            assert_eq!(
+                compare_to_duty(duty_to_compare(duty, MAX_ARR_16), MAX_ARR_16),
+                duty
+            );
+        }
+    }
+
+    #[test]
+    fn pwm_boundaries() {
+        assert_eq!(pwm(16_000_000, 0, MAX_ARR_16), Err(TimerError::TooFast));
+        assert_eq!(
+            pwm(16_000_000, 16_000_001, MAX_ARR_16),
+            Err(TimerError::TooFast)
+        );
+        assert_eq!(
+            pwm(16_000_000, 16_000_000, MAX_ARR_16),
+            Err(TimerError::TooFast)
+        );
+        assert_eq!(
+            pwm(16_000_000, 8_000_000, MAX_ARR_16),
+            Ok(TimerSettings { psc: 0, arr: 1 })
+        );
+        assert_eq!(pwm(84_000_000, 1, 1000), Err(TimerError::TooSlow));
+    }
+}
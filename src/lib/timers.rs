@@ -1,15 +1,39 @@
-use core::cell::RefCell;
-use cortex_m::interrupt::{free, Mutex};
-use stm32f4::stm32f401::{TIM2, TIM3, TIM4, TIM5, TIM9};
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{free, CriticalSection, Mutex};
+use stm32f4::stm32f401::{tim3, TIM1, TIM2, TIM3, TIM4, TIM5, TIM9};
 
-// The internal clock is running at 16MHz.
+use crate::clock;
+use crate::distance::{Edge, G_DISTANCES, SENSOR_COUNT};
+use crate::pins::{TriggerOutput, ULTRASOUND_SENSORS};
+use crate::rcc::Clocks;
+use crate::robot::{signed_duty, Dir, StopMode};
+use crate::timebase::{self, TimerError, MAX_ARR_16};
+use crate::trigger::{TriggerScheduler, DEFAULT_TRIGGER_INTERVAL_MS};
 
 pub static G_TIM4: Mutex<RefCell<Option<TIM4>>> = Mutex::new(RefCell::new(None));
 pub static G_TIM2: Mutex<RefCell<Option<TIM2>>> = Mutex::new(RefCell::new(None));
 pub static G_TIM5: Mutex<RefCell<Option<TIM5>>> = Mutex::new(RefCell::new(None));
+pub static G_TIM9: Mutex<RefCell<Option<TIM9>>> = Mutex::new(RefCell::new(None));
+pub static G_TIM1: Mutex<RefCell<Option<TIM1>>> = Mutex::new(RefCell::new(None));
+/// The sensors of `pins::ULTRASOUND_SENSORS` take turns.
+static G_TRIGGER: Mutex<RefCell<TriggerScheduler>> =
+    Mutex::new(RefCell::new(TriggerScheduler::new(SENSOR_COUNT)));
+/// Time between two trigger pulses, 0 with the triggering stopped.
+static G_TRIGGER_INTERVAL_MS: Mutex<Cell<u16>> = Mutex::new(Cell::new(DEFAULT_TRIGGER_INTERVAL_MS));
+/// How the TIM2 and TIM5 interrupts stop the motors once their locks complete.
+static G_LOCK_STOP_MODE: Mutex<Cell<StopMode>> = Mutex::new(Cell::new(StopMode::Coast));
+/// Number of times TIM4 wrapped around, extending its count to 32 bits.
+static G_TIM4_OVERFLOWS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
 
-/// Configure TIM3 to manage PWM for the motors.
-pub fn configure_tim3(tim: &TIM3) {
+/// Above hearing, leaving 4200 steps of duty with the timers at 84 MHz.
+pub const DEFAULT_MOTOR_PWM_HZ: u32 = 20_000;
+/// Largest TIM3 reload, one below the register's so a compare of ARR + 1 fits and keeps a channel fully on for braking.
+const MAX_MOTOR_ARR: u32 = MAX_ARR_16 - 1;
+
+/// Configure TIM3 to manage PWM for the motors at `pwm_hz`, with the finest resolution the clocks allow.
+/// The duties are scaled to the resolution, see `set_left_motor_duty`.
+pub fn configure_tim3(tim: &TIM3, clocks: &Clocks, pwm_hz: u32) -> Result<(), TimerError> {
+    let settings = timebase::pwm(clocks.timer1_hz(), pwm_hz, MAX_MOTOR_ARR)?;
     // Configure channels as outputs in PWM mode
     tim.ccmr1_output().write(|w| {
         w.cc1s().output();
@@ -31,64 +55,215 @@ pub fn configure_tim3(tim: &TIM3) {
         w.cc3e().set_bit();
         w.cc4e().set_bit()
     });
-    tim.arr.write(|w| w.arr().bits(u16::MAX));
+    tim.psc.write(|w| w.psc().bits(settings.psc));
+    tim.arr.write(|w| w.arr().bits(settings.arr as u16));
     tim.cr1.modify(|_, w| w.cen().enabled());
+    Ok(())
 }
 
-/// Configure TIM9 to send a pulse every 70ms to the ultrasonic sensor.
-pub fn configure_tim9(tim: &TIM9) {
-    tim.psc.write(|w| w.psc().bits(159)); // Each tick is 10us
-    tim.arr.write(|w| unsafe { w.arr().bits(7000) }); // Period of 70ms
-    tim.ccr
-        .iter()
-        .for_each(|ccr| ccr.write(|w| unsafe { w.ccr().bits(2) })); // We just need > 10us
+/// The trigger timers tick every 10us.
+const TRIGGER_TICK_HZ: u32 = 100_000;
+/// Length of the trigger pulses in TIM9 ticks, we just need > 10us.
+const TRIGGER_PULSE_TICKS: u16 = 2;
 
-    tim.ccmr1_output().write(|w| unsafe {
+/// Configure TIM9 and TIM1 to send a pulse to one of the ultrasonic sensors every 40ms.
+/// Both run in step, so the sensors triggered by either of them take turns, see `tim9_interrupt_handler`.
+pub fn configure_trigger_timers(
+    tim9: &TIM9,
+    tim1: &TIM1,
+    clocks: &Clocks,
+) -> Result<(), TimerError> {
+    let settings = timebase::TimerSettings {
+        psc: timebase::prescaler(clocks.timer2_hz(), TRIGGER_TICK_HZ)?,
+        arr: trigger_reload(DEFAULT_TRIGGER_INTERVAL_MS)?,
+    };
+    tim9.psc.write(|w| w.psc().bits(settings.psc));
+    tim9.arr
+        .write(|w| unsafe { w.arr().bits(settings.arr as u16) });
+    // No pulses until the interrupt picks a sensor
+    tim9.ccr
+        .iter()
+        .for_each(|ccr| ccr.write(|w| unsafe { w.ccr().bits(0) }));
+    tim9.ccmr1_output().write(|w| unsafe {
         // Configure channels as outputs
         w.cc1s().bits(0b00);
         w.cc2s().bits(0b00);
-        // Enable PWM Mode 1
-        w.oc1m().bits(0b111);
-        w.oc2m().bits(0b111)
+        // Enable PWM Mode 1, high for the first CCR ticks of each period
+        w.oc1m().bits(0b110);
+        w.oc2m().bits(0b110);
+        // Changes to CCR take effect at the next period
+        w.oc1pe().set_bit();
+        w.oc2pe().set_bit()
     });
     // Enable the output channels.
-    tim.ccer.write(|w| w.cc1e().set_bit().cc2e().set_bit());
-    tim.cr1.modify(|_, w| w.cen().enabled());
+    tim9.ccer.write(|w| w.cc1e().set_bit().cc2e().set_bit());
+    tim9.dier.write(|w| w.uie().set_bit());
+    tim9.cr1.modify(|_, w| w.arpe().set_bit());
+
+    // Only the TIM1 channels used by a sensor are outputs, the others keep their pins.
+    // PWM mode 1 with preload is 0x68 in the byte of each channel in CCMR1 and CCMR2.
+    let (mut ccmr, mut ccer) = (0u64, 0u32);
+    ULTRASOUND_SENSORS
+        .iter()
+        .filter_map(|sensor| match sensor.trigger {
+            TriggerOutput::Tim1(channel) => Some(channel - 1),
+            TriggerOutput::Tim9(_) => None,
+        })
+        .for_each(|ch| {
+            ccmr |= 0x68 << (8 * ch);
+            ccer |= 1 << (4 * ch);
+        });
+    tim1.psc.write(|w| w.psc().bits(settings.psc));
+    tim1.arr.write(|w| unsafe { w.bits(settings.arr) });
+    tim1.ccr
+        .iter()
+        .for_each(|ccr| ccr.write(|w| unsafe { w.bits(0) }));
+    tim1.ccmr1_output()
+        .write(|w| unsafe { w.bits(ccmr as u32 & 0xFFFF) });
+    tim1.ccmr2_output()
+        .write(|w| unsafe { w.bits((ccmr >> 16) as u32 & 0xFFFF) });
+    tim1.ccer.write(|w| unsafe { w.bits(ccer) });
+    // The outputs of the advanced timer are only driven with the main output enabled
+    tim1.bdtr.modify(|_, w| w.moe().set_bit());
+    tim1.cr1.modify(|_, w| w.arpe().set_bit());
+
+    start_trigger_timers(tim9, tim1);
+    Ok(())
 }
 
-/// Configure TIM4 to measure pulse lengths of the ultrasound sensors.
-pub fn configure_tim4(tim: &TIM4) {
-    // Enable cc interrupts
-    tim.dier.write(|w| w.cc3ie().enabled().cc4ie().enabled());
-    // Select input channels for CC triggers
-    tim.ccmr2_input().write(|w| {
-        w.cc3s()
-            .ti3()
-            .cc4s()
-            .ti4()
-            .ic3f()
-            .bits(0b0111)
-            .ic4f()
-            .bits(0b0111)
-    });
-    tim.arr.write(|w| w.arr().bits(u16::MAX));
-    tim.psc.write(|w| w.psc().bits(15));
-    tim.ccer.write(|w| {
-        // Enable channels
-        w.cc3e().set_bit();
-        w.cc4e().set_bit();
-        // Trigger at both rising and falling edge
-        w.cc3p().set_bit();
-        w.cc3np().set_bit();
-        w.cc4p().set_bit();
-        w.cc4np().set_bit()
+/// The auto-reload of the trigger timers firing every `interval_ms`.
+fn trigger_reload(interval_ms: u16) -> Result<u32, TimerError> {
+    timebase::reload_for_ms(TRIGGER_TICK_HZ, interval_ms.into(), MAX_ARR_16)
+}
+
+/// TIM1 starts first, so its update events come just before the ones of TIM9 and the CCRs
+/// written by `tim9_interrupt_handler` are always applied one period later on both.
+fn start_trigger_timers(tim9: &TIM9, tim1: &TIM1) {
+    tim1.cr1.modify(|_, w| w.cen().set_bit());
+    tim9.cr1.modify(|_, w| w.cen().enabled());
+}
+
+/// Set the time between two trigger pulses, 0 stops triggering. The stale limit of the distances follows it.
+/// Each sensor is triggered once every `interval_ms` times the number of enabled sensors.
+/// Anything above 655ms does not fit in the 16 bit TIM9 and is refused, leaving the interval unchanged.
+pub fn set_trigger_interval(interval_ms: u16) -> Result<(), TimerError> {
+    free(|cs| {
+        let some_tim9 = G_TIM9.borrow(cs).borrow();
+        let tim9 = some_tim9.as_ref().unwrap();
+        let some_tim1 = G_TIM1.borrow(cs).borrow();
+        let tim1 = some_tim1.as_ref().unwrap();
+        if interval_ms == 0 {
+            tim9.cr1.modify(|_, w| w.cen().disabled());
+            tim1.cr1.modify(|_, w| w.cen().clear_bit());
+            tim9.ccr
+                .iter()
+                .for_each(|ccr| ccr.write(|w| unsafe { w.ccr().bits(0) }));
+            tim1.ccr
+                .iter()
+                .for_each(|ccr| ccr.write(|w| unsafe { w.bits(0) }));
+        } else {
+            let arr = trigger_reload(interval_ms)?;
+            tim9.arr.write(|w| unsafe { w.arr().bits(arr as u16) });
+            tim1.arr.write(|w| unsafe { w.bits(arr) });
+            if tim9.cr1.read().cen().bit_is_clear() {
+                start_trigger_timers(tim9, tim1);
+            }
+        }
+        G_TRIGGER_INTERVAL_MS.borrow(cs).set(interval_ms);
+        update_stale_limit(cs);
+        Ok(())
+    })
+}
+
+/// Include or leave out an ultrasound sensor from the turns, indexed like `distance::Measurements`.
+pub fn set_trigger_enabled(sensor: usize, enabled: bool) {
+    free(|cs| {
+        G_TRIGGER
+            .borrow(cs)
+            .borrow_mut()
+            .set_enabled(sensor, enabled);
+        update_stale_limit(cs);
+    });
+}
+
+/// The sensors are triggered less often with a longer interval or more of them taking turns,
+/// so their readings are given longer before they are stale.
+fn update_stale_limit(cs: &CriticalSection) {
+    let interval_ms = G_TRIGGER_INTERVAL_MS.borrow(cs).get();
+    let stale_after_us = G_TRIGGER.borrow(cs).borrow().stale_after_us(interval_ms);
+    G_DISTANCES
+        .borrow(cs)
+        .borrow_mut()
+        .set_stale_after_us(stale_after_us);
+}
+
+pub fn init_global_trigger_timers(tim9: TIM9, tim1: TIM1) {
+    free(|cs| {
+        G_TIM9.borrow(cs).replace(Some(tim9));
+        G_TIM1.borrow(cs).replace(Some(tim1));
     });
+}
+
+/// Pick the sensor triggered in the next period, the preloaded CCRs are applied at the update event.
+pub fn tim9_interrupt_handler() {
+    free(|cs| {
+        let some_tim9 = G_TIM9.borrow(cs).borrow();
+        let tim9 = some_tim9.as_ref().unwrap();
+        let some_tim1 = G_TIM1.borrow(cs).borrow();
+        let tim1 = some_tim1.as_ref().unwrap();
+        tim9.sr.modify(|_, w| w.uif().clear_bit());
+        let next = G_TRIGGER.borrow(cs).borrow_mut().next_slot();
+        ULTRASOUND_SENSORS
+            .iter()
+            .enumerate()
+            .for_each(|(i, sensor)| {
+                let ticks = if next == Some(i) {
+                    TRIGGER_PULSE_TICKS
+                } else {
+                    0
+                };
+                match sensor.trigger {
+                    TriggerOutput::Tim9(ch) => {
+                        tim9.ccr[ch - 1].write(|w| unsafe { w.ccr().bits(ticks) })
+                    }
+                    TriggerOutput::Tim1(ch) => {
+                        tim1.ccr[ch - 1].write(|w| unsafe { w.bits(ticks.into()) })
+                    }
+                }
+            });
+    });
+}
+
+/// Configure TIM4 to measure pulse lengths of the ultrasound sensors, on the channels in `pins::ULTRASOUND_SENSORS`.
+/// It counts microseconds and its overflows are counted as well, see `tim4_micros`.
+pub fn configure_tim4(tim: &TIM4, clocks: &Clocks) -> Result<(), TimerError> {
+    let settings = timebase::periodic(clocks.timer1_hz(), 1_000_000, MAX_ARR_16 + 1, MAX_ARR_16)?;
+    // The fields are named after the channels, so they are set through the raw bits:
+    // each channel captures its own input filtered over 8 samples (0x71 in CCMR),
+    // is enabled and triggers at both rising and falling edge (CCxE, CCxP and CCxNP in CCER)
+    // and interrupts on captures (CCxIE in DIER, next to UIE).
+    let (mut ccmr, mut ccer, mut dier) = (0u64, 0u32, 1u32);
+    ULTRASOUND_SENSORS.iter().for_each(|sensor| {
+        let ch = sensor.echo_channel - 1;
+        ccmr |= 0x71 << (8 * ch);
+        ccer |= 0b1011 << (4 * ch);
+        dier |= 1 << (ch + 1);
+    });
+    tim.ccmr1_input()
+        .write(|w| unsafe { w.bits(ccmr as u32 & 0xFFFF) });
+    tim.ccmr2_input()
+        .write(|w| unsafe { w.bits((ccmr >> 16) as u32 & 0xFFFF) });
+    tim.arr.write(|w| w.arr().bits(settings.arr as u16));
+    tim.psc.write(|w| w.psc().bits(settings.psc));
+    tim.ccer.write(|w| unsafe { w.bits(ccer) });
+    tim.dier.write(|w| unsafe { w.bits(dier) });
     tim.cr1.modify(|_, w| w.cen().enabled());
+    Ok(())
 }
 
-/// Configure TIM2 to make the left motor run a certain number of rotations.
+/// Configure TIM2 to count the ticks of the left speed encoder.
+/// CH3 compares against the count to stop the left motor after a number of ticks.
 pub fn configure_tim2(tim: &TIM2) {
-    tim.cr1.write(|w| w.opm().enabled());
     tim.smcr.write(|w| {
         w.ts().ti1fp1();
         w.sms().ext_clock_mode()
@@ -101,12 +276,13 @@ pub fn configure_tim2(tim: &TIM2) {
         w.cc1p().clear_bit();
         w.cc1np().clear_bit()
     });
-    tim.dier.write(|w| w.uie().enabled());
+    tim.arr.write(|w| w.arr().bits(u32::MAX));
+    tim.cr1.modify(|_, w| w.cen().enabled());
 }
 
-/// Configure TIM5 to make the right motor run a certain number of rotations.
+/// Configure TIM5 to count the ticks of the right speed encoder.
+/// CH3 compares against the count to stop the right motor after a number of ticks.
 pub fn configure_tim5(tim: &TIM5) {
-    tim.cr1.write(|w| w.opm().enabled());
     tim.smcr.write(|w| {
         w.ts().ti2fp2();
         w.sms().ext_clock_mode()
@@ -119,74 +295,261 @@ pub fn configure_tim5(tim: &TIM5) {
         w.cc1p().clear_bit();
         w.cc1np().clear_bit()
     });
-    tim.dier.write(|w| w.uie().enabled());
+    tim.arr.write(|w| w.arr().bits(u32::MAX));
+    tim.cr1.modify(|_, w| w.cen().enabled());
 }
 
-pub fn tim4_interrupt_handler() {
-    let (front, side) = free(|cs| {
-        let some_tim4 = G_TIM4.borrow(cs).borrow();
-        let tim4 = some_tim4.as_ref().unwrap();
-        let sr = &tim4.sr;
-        let front = sr
+/// Total number of ticks counted by the left speed encoder.
+pub fn left_encoder_ticks() -> u32 {
+    free(|cs| {
+        G_TIM2
+            .borrow(cs)
+            .borrow()
+            .as_ref()
+            .unwrap()
+            .cnt
             .read()
-            .cc3if()
-            .bit_is_set()
-            .then_some(tim4.ccr3().read().ccr().bits());
-        let side = sr
+            .bits()
+    })
+}
+
+/// Total number of ticks counted by the right speed encoder.
+pub fn right_encoder_ticks() -> u32 {
+    free(|cs| {
+        G_TIM5
+            .borrow(cs)
+            .borrow()
+            .as_ref()
+            .unwrap()
+            .cnt
             .read()
-            .cc4if()
-            .bit_is_set()
-            .then_some(tim4.ccr4().read().ccr().bits());
-        sr.modify(|_, w| {
-            w.cc3of()
-                .clear_bit()
-                .cc4of()
-                .clear_bit()
-                .cc3if()
-                .clear_bit()
-                .cc4if()
-                .clear_bit()
-        });
-        (front, side)
+            .bits()
+    })
+}
+
+/// Extend a count of TIM4 to 32 bits.
+/// A count taken just after the timer wrapped belongs to the next period if the overflow is still pending.
+fn extend_tim4_count(overflows: u32, pending_overflow: bool, count: u16) -> u32 {
+    let overflows = if pending_overflow && count < 0x8000 {
+        overflows.wrapping_add(1)
+    } else {
+        overflows
+    };
+    (overflows << 16) | count as u32
+}
+
+/// Microseconds since TIM4 was started, wrapping around after about 71 minutes.
+/// Only used to tell how long ago a capture was taken, see `clock::micros` for the time base.
+fn tim4_micros() -> u32 {
+    free(|cs| {
+        let some_tim4 = G_TIM4.borrow(cs).borrow();
+        let tim4 = some_tim4.as_ref().unwrap();
+        let count = tim4.cnt.read().bits() as u16;
+        let pending_overflow = tim4.sr.read().uif().bit_is_set();
+        extend_tim4_count(G_TIM4_OVERFLOWS.borrow(cs).get(), pending_overflow, count)
+    })
+}
+
+/// Stop the left motor once its encoder counts another `ticks` ticks, or right away for 0.
+pub fn lock_left_motor(ticks: u32) {
+    free(|cs| {
+        let some_tim2 = G_TIM2.borrow(cs).borrow();
+        let tim2 = some_tim2.as_ref().unwrap();
+        if ticks == 0 {
+            tim2.dier.modify(|_, w| w.cc3ie().disabled());
+            stop_left_motor_locked(cs);
+            return;
+        }
+        let target = tim2.cnt.read().bits().wrapping_add(ticks);
+        tim2.ccr3().write(|w| w.bits(target));
+        tim2.sr.modify(|_, w| w.cc3if().clear_bit());
+        tim2.dier.modify(|_, w| w.cc3ie().enabled());
     });
+}
+
+/// Stop the right motor once its encoder counts another `ticks` ticks, or right away for 0.
+pub fn lock_right_motor(ticks: u32) {
     free(|cs| {
-        use crate::distance::G_DISTANCES;
-        let mut distances = G_DISTANCES.borrow(cs).borrow_mut();
-        front
-            .into_iter()
-            .for_each(|t| distances.front.update_measurment(t));
-        side.into_iter()
-            .for_each(|t| distances.side.update_measurment(t));
+        let some_tim5 = G_TIM5.borrow(cs).borrow();
+        let tim5 = some_tim5.as_ref().unwrap();
+        if ticks == 0 {
+            tim5.dier.modify(|_, w| w.cc3ie().disabled());
+            stop_right_motor_locked(cs);
+            return;
+        }
+        let target = tim5.cnt.read().bits().wrapping_add(ticks);
+        tim5.ccr3().write(|w| w.bits(target));
+        tim5.sr.modify(|_, w| w.cc3if().clear_bit());
+        tim5.dier.modify(|_, w| w.cc3ie().enabled());
     });
 }
 
-pub fn tim2_interrupt_handler() {
+/// Forget the locks of both motors, leaving them running.
+pub fn unlock_motors() {
+    free(|cs| {
+        let some_tim2 = G_TIM2.borrow(cs).borrow();
+        let some_tim5 = G_TIM5.borrow(cs).borrow();
+        some_tim2
+            .as_ref()
+            .unwrap()
+            .dier
+            .modify(|_, w| w.cc3ie().disabled());
+        some_tim5
+            .as_ref()
+            .unwrap()
+            .dier
+            .modify(|_, w| w.cc3ie().disabled());
+    });
+}
+
+/// Whether the left motor is still waiting for its lock to complete.
+pub fn is_left_motor_locked() -> bool {
     free(|cs| {
         G_TIM2
             .borrow(cs)
             .borrow()
             .as_ref()
             .unwrap()
-            .sr
-            .modify(|_, w| w.uif().clear());
-        let tim3 = unsafe { &*TIM3::PTR };
-        tim3.ccr3().reset();
-        tim3.ccr4().reset();
-    });
+            .dier
+            .read()
+            .cc3ie()
+            .is_enabled()
+    })
 }
 
-pub fn tim5_interrupt_handler() {
+/// Whether the right motor is still waiting for its lock to complete.
+pub fn is_right_motor_locked() -> bool {
     free(|cs| {
         G_TIM5
             .borrow(cs)
             .borrow()
             .as_ref()
             .unwrap()
-            .sr
-            .modify(|_, w| w.uif().clear());
-        let tim3 = unsafe { &*TIM3::PTR };
-        tim3.ccr1().reset();
-        tim3.ccr2().reset();
+            .dier
+            .read()
+            .cc3ie()
+            .is_enabled()
+    })
+}
+
+fn tim4_capture(tim4: &TIM4, channel: usize) -> u16 {
+    let ccr = match channel {
+        1 => tim4.ccr1(),
+        2 => tim4.ccr2(),
+        3 => tim4.ccr3(),
+        _ => tim4.ccr4(),
+    };
+    ccr.read().ccr().bits()
+}
+
+pub fn tim4_interrupt_handler() {
+    let captures = free(|cs| {
+        let some_tim4 = G_TIM4.borrow(cs).borrow();
+        let tim4 = some_tim4.as_ref().unwrap();
+        let sr = tim4.sr.read().bits();
+        let overflows = G_TIM4_OVERFLOWS.borrow(cs);
+        let pending_overflow = sr & 1 != 0;
+        // Flags to clear, CCxIF is bit x and CCxOF bit 8 + x
+        let mut handled = u32::from(pending_overflow);
+        let mut captures = [None; SENSOR_COUNT];
+        ULTRASOUND_SENSORS
+            .iter()
+            .zip(captures.iter_mut())
+            .for_each(|(sensor, capture)| {
+                let ch = sensor.echo_channel;
+                if sr & (1 << ch) == 0 {
+                    return;
+                }
+                let t = tim4_capture(tim4, ch);
+                // The level of the echo pin tells which edge was captured
+                let edge = if sensor.echo_pin.is_high() {
+                    Edge::Rising
+                } else {
+                    Edge::Falling
+                };
+                let overcaptured = sr & (1 << (8 + ch)) != 0;
+                *capture = Some((
+                    extend_tim4_count(overflows.get(), pending_overflow, t),
+                    edge,
+                    overcaptured,
+                ));
+                handled |= (1 << ch) | (1 << (8 + ch));
+            });
+        if pending_overflow {
+            overflows.set(overflows.get().wrapping_add(1));
+        }
+        // Writing ones leaves the flags alone, so nothing raised after reading SR is lost
+        tim4.sr.write(|w| unsafe { w.bits(!handled) });
+        captures
+    });
+    // Date the captures with the clock, by how long ago TIM4 took them
+    let (tim4_now, now) = free(|_| (tim4_micros(), clock::micros()));
+    let captures = captures.map(|capture| {
+        capture.map(|(t, edge, overcaptured)| {
+            let age = tim4_now.wrapping_sub(t);
+            (now.wrapping_sub(age), edge, overcaptured)
+        })
+    });
+    free(|cs| {
+        use crate::distance::G_DISTANCES;
+        let mut distances = G_DISTANCES.borrow(cs).borrow_mut();
+        captures
+            .iter()
+            .zip(distances.iter_mut())
+            .for_each(|(capture, measurer)| {
+                if let Some((t, edge, overcaptured)) = *capture {
+                    measurer.update_measurment(t, edge, overcaptured);
+                }
+            });
+    });
+}
+
+/// Choose between coasting and braking when a lock completes, for both motors.
+pub fn set_lock_stop_mode(mode: StopMode) {
+    free(|cs| G_LOCK_STOP_MODE.borrow(cs).set(mode));
+}
+
+/// The value both CCRs of a motor are set to when its lock completes.
+fn lock_stop_compare(tim3: &tim3::RegisterBlock, mode: StopMode) -> u16 {
+    match mode {
+        StopMode::Coast => 0,
+        StopMode::Brake => timebase::full_on_compare(tim3.arr.read().bits()) as u16,
+    }
+}
+
+/// Stop the left motor the way set by `set_lock_stop_mode`.
+fn stop_left_motor_locked(cs: &CriticalSection) {
+    let tim3 = unsafe { &*TIM3::PTR };
+    let compare = lock_stop_compare(tim3, G_LOCK_STOP_MODE.borrow(cs).get());
+    tim3.ccr3().write(|w| w.ccr().bits(compare));
+    tim3.ccr4().write(|w| w.ccr().bits(compare));
+}
+
+/// Stop the right motor the way set by `set_lock_stop_mode`.
+fn stop_right_motor_locked(cs: &CriticalSection) {
+    let tim3 = unsafe { &*TIM3::PTR };
+    let compare = lock_stop_compare(tim3, G_LOCK_STOP_MODE.borrow(cs).get());
+    tim3.ccr1().write(|w| w.ccr().bits(compare));
+    tim3.ccr2().write(|w| w.ccr().bits(compare));
+}
+
+pub fn tim2_interrupt_handler() {
+    free(|cs| {
+        let some_tim2 = G_TIM2.borrow(cs).borrow();
+        let tim2 = some_tim2.as_ref().unwrap();
+        tim2.dier.modify(|_, w| w.cc3ie().disabled());
+        tim2.sr.modify(|_, w| w.cc3if().clear_bit());
+        stop_left_motor_locked(cs);
+    });
+}
+
+pub fn tim5_interrupt_handler() {
+    free(|cs| {
+        let some_tim5 = G_TIM5.borrow(cs).borrow();
+        let tim5 = some_tim5.as_ref().unwrap();
+        tim5.dier.modify(|_, w| w.cc3ie().disabled());
+        tim5.sr.modify(|_, w| w.cc3if().clear_bit());
+        stop_right_motor_locked(cs);
     });
 }
 
@@ -198,39 +561,40 @@ pub fn init_global_timers(tim4: TIM4, tim2: TIM2, tim5: TIM5) {
     });
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Direction {
-    Forward,
-    Backward,
+/// The compare value of a TIM3 channel for `duty` out of `u16::MAX`.
+fn motor_compare(tim: &TIM3, duty: u16) -> u16 {
+    timebase::duty_to_compare(duty, tim.arr.read().bits()) as u16
 }
 
-pub fn set_left_motor(tim: &TIM3, mut duty: i32) {
-    duty = duty.clamp(-(u16::MAX as i32), u16::MAX as i32);
-    let duties = if duty > 0 { (duty, 0) } else { (0, duty) };
-    tim.ccr3().write(|w| w.ccr().bits(duties.0 as u16));
-    tim.ccr4().write(|w| w.ccr().bits(duties.1 as u16));
+/// Drive the left motor forward for positive speeds and backward for negative ones, see `robot::signed_duty`.
+pub fn set_left_motor(tim: &TIM3, speed: i32) {
+    let (duty, dir) = signed_duty(speed);
+    set_left_motor_duty(tim, duty, dir);
 }
 
-pub fn set_right_motor(tim: &TIM3, mut duty: i32) {
-    duty = duty.clamp(-(u16::MAX as i32), u16::MAX as i32);
-    let duties = if duty > 0 { (duty, 0) } else { (0, duty) };
-    tim.ccr1().write(|w| w.ccr().bits(duties.0 as u16));
-    tim.ccr2().write(|w| w.ccr().bits(duties.1 as u16));
+/// Drive the right motor forward for positive speeds and backward for negative ones, see `robot::signed_duty`.
+pub fn set_right_motor(tim: &TIM3, speed: i32) {
+    let (duty, dir) = signed_duty(speed);
+    set_right_motor_duty(tim, duty, dir);
 }
 
-pub fn set_left_motor_duty(tim: &TIM3, duty: u16, direction: Direction) {
-    let values = match direction {
-        Direction::Forward => (duty, 0),
-        Direction::Backward => (0, duty),
+/// Drive the left motor at `duty` out of `u16::MAX`, whatever the resolution of TIM3.
+pub fn set_left_motor_duty(tim: &TIM3, duty: u16, dir: Dir) {
+    let duty = motor_compare(tim, duty);
+    let values = match dir {
+        Dir::Fd => (duty, 0),
+        Dir::Bk => (0, duty),
     };
     tim.ccr3().write(|w| w.ccr().bits(values.0));
     tim.ccr4().write(|w| w.ccr().bits(values.1));
 }
 
-pub fn set_right_motor_duty(tim: &TIM3, duty: u16, direction: Direction) {
-    let values = match direction {
-        Direction::Forward => (duty, 0),
-        Direction::Backward => (0, duty),
+/// Drive the right motor at `duty` out of `u16::MAX`, whatever the resolution of TIM3.
+pub fn set_right_motor_duty(tim: &TIM3, duty: u16, dir: Dir) {
+    let duty = motor_compare(tim, duty);
+    let values = match dir {
+        Dir::Fd => (duty, 0),
+        Dir::Bk => (0, duty),
     };
     tim.ccr1().write(|w| w.ccr().bits(values.0));
     tim.ccr2().write(|w| w.ccr().bits(values.1));
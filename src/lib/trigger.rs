@@ -0,0 +1,122 @@
+/// Most ultrasound sensors a `TriggerScheduler` can take turns between.
+pub const MAX_SENSORS: usize = 8;
+/// Longer than the 38ms the sensor waits for an echo, so it is done before the next one is triggered.
+pub const DEFAULT_TRIGGER_INTERVAL_MS: u16 = 40;
+/// A reading goes stale once its sensor missed this many of its turns.
+const STALE_AFTER_TURNS: u32 = 3;
+
+/// Takes turns between the ultrasound sensors, so only one of them is listening for its echo at a time.
+/// Every slot of the trigger timer fires at most one sensor, in the order of their indices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TriggerScheduler {
+    sensors: usize,
+    /// Bit `i` is set if sensor `i` is triggered.
+    enabled: u8,
+    next: usize,
+}
+
+impl TriggerScheduler {
+    /// All the `sensors` start enabled. Clamped to `MAX_SENSORS`.
+    pub const fn new(sensors: usize) -> Self {
+        let sensors = if sensors > MAX_SENSORS {
+            MAX_SENSORS
+        } else {
+            sensors
+        };
+        Self {
+            sensors,
+            enabled: ((1u16 << sensors) - 1) as u8,
+            next: 0,
+        }
+    }
+
+    pub fn sensors(&self) -> usize {
+        self.sensors
+    }
+
+    /// Disabled sensors are skipped, so the others are triggered more often.
+    pub fn set_enabled(&mut self, sensor: usize, enabled: bool) {
+        if sensor >= self.sensors {
+            return;
+        }
+        if enabled {
+            self.enabled |= 1 << sensor;
+        } else {
+            self.enabled &= !(1 << sensor);
+        }
+    }
+
+    pub const fn enabled_sensors(&self) -> usize {
+        self.enabled.count_ones() as usize
+    }
+
+    /// How long after its last echo a reading is stale, with a slot every `interval_ms`.
+    /// 0 once triggering stopped or every sensor is disabled, as no reading will be refreshed.
+    pub const fn stale_after_us(&self, interval_ms: u16) -> u32 {
+        STALE_AFTER_TURNS * interval_ms as u32 * 1000 * self.enabled_sensors() as u32
+    }
+
+    pub fn is_enabled(&self, sensor: usize) -> bool {
+        sensor < self.sensors && self.enabled & (1 << sensor) != 0
+    }
+
+    /// The sensor to trigger in the next slot, `None` if all of them are disabled.
+    pub fn next_slot(&mut self) -> Option<usize> {
+        for _ in 0..self.sensors {
+            let sensor = self.next;
+            self.next = (self.next + 1) % self.sensors;
+            if self.is_enabled(sensor) {
+                return Some(sensor);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slots<const N: usize>(scheduler: &mut TriggerScheduler) -> [Option<usize>; N] {
+        [(); N].map(|_| scheduler.next_slot())
+    }
+
+    #[test]
+    fn takes_turns_in_order() {
+        let mut scheduler = TriggerScheduler::new(3);
+        assert_eq!(slots::<7>(&mut scheduler), [0, 1, 2, 0, 1, 2, 0].map(Some));
+    }
+
+    #[test]
+    fn skips_disabled_sensors() {
+        let mut scheduler = TriggerScheduler::new(3);
+        scheduler.set_enabled(1, false);
+        assert_eq!(slots::<4>(&mut scheduler), [0, 2, 0, 2].map(Some));
+        assert_eq!(scheduler.enabled_sensors(), 2);
+
+        scheduler.set_enabled(1, true);
+        // The turn carries on from where it was
+        assert_eq!(slots::<3>(&mut scheduler), [0, 1, 2].map(Some));
+    }
+
+    #[test]
+    fn nothing_to_trigger_when_all_are_disabled() {
+        let mut scheduler = TriggerScheduler::new(3);
+        (0..3).for_each(|sensor| scheduler.set_enabled(sensor, false));
+        assert_eq!(scheduler.next_slot(), None);
+        assert_eq!(scheduler.stale_after_us(DEFAULT_TRIGGER_INTERVAL_MS), 0);
+
+        scheduler.set_enabled(2, true);
+        assert_eq!(scheduler.next_slot(), Some(2));
+        assert_eq!(scheduler.next_slot(), Some(2));
+    }
+
+    #[test]
+    fn out_of_range_sensors_are_ignored() {
+        let mut scheduler = TriggerScheduler::new(MAX_SENSORS + 1);
+        assert_eq!(scheduler.sensors(), MAX_SENSORS);
+        scheduler.set_enabled(MAX_SENSORS, false);
+        assert_eq!(scheduler.enabled_sensors(), MAX_SENSORS);
+        assert!(!scheduler.is_enabled(MAX_SENSORS));
+    }
+}
@@ -0,0 +1,120 @@
+use core::cell::RefCell;
+use cortex_m::interrupt::{free, Mutex};
+use stm32f4::stm32f401::USART1;
+
+use crate::rcc::Clocks;
+
+const BUFFER_LEN: usize = 128;
+
+pub static G_USART1: Mutex<RefCell<Option<USART1>>> = Mutex::new(RefCell::new(None));
+static G_RX: Mutex<RefCell<RingBuffer>> = Mutex::new(RefCell::new(RingBuffer::new()));
+static G_TX: Mutex<RefCell<RingBuffer>> = Mutex::new(RefCell::new(RingBuffer::new()));
+
+/// Fixed size FIFO of bytes shared between the main loop and the interrupt handler.
+struct RingBuffer {
+    buf: [u8; BUFFER_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; BUFFER_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn free_space(&self) -> usize {
+        BUFFER_LEN - self.len
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == BUFFER_LEN {
+            return false;
+        }
+        self.buf[(self.head + self.len) % BUFFER_LEN] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % BUFFER_LEN;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// Configure USART1 for 8N1 at the given baud rate, receiving and sending in its interrupt.
+pub fn configure_usart1(usart: &USART1, baud: u32, clocks: &Clocks) {
+    // With 16x oversampling the divider is simply the clock over the baud rate
+    let pclk2_hz = clocks.pclk2_hz;
+    usart
+        .brr
+        .write(|w| unsafe { w.bits((pclk2_hz + baud / 2) / baud) });
+    usart.cr2.reset();
+    usart.cr3.reset();
+    usart.cr1.write(|w| {
+        w.ue().set_bit();
+        w.te().set_bit();
+        w.re().set_bit();
+        w.rxneie().set_bit()
+    });
+}
+
+pub fn init_global_usart(usart: USART1) {
+    free(|cs| {
+        G_USART1.borrow(cs).replace(Some(usart));
+    });
+}
+
+/// Queue bytes for sending. Returns false without queueing anything if they do not fit.
+pub fn write(bytes: &[u8]) -> bool {
+    free(|cs| {
+        let mut tx = G_TX.borrow(cs).borrow_mut();
+        if tx.free_space() < bytes.len() {
+            return false;
+        }
+        bytes.iter().for_each(|&b| {
+            tx.push(b);
+        });
+        let some_usart = G_USART1.borrow(cs).borrow();
+        let usart = some_usart.as_ref().unwrap();
+        usart.cr1.modify(|_, w| w.txeie().set_bit());
+        true
+    })
+}
+
+/// Whether everything queued by `write` has been handed to the USART.
+pub fn is_tx_idle() -> bool {
+    free(|cs| G_TX.borrow(cs).borrow().len == 0)
+}
+
+/// Take the oldest received byte, if any.
+pub fn read() -> Option<u8> {
+    free(|cs| G_RX.borrow(cs).borrow_mut().pop())
+}
+
+pub fn usart1_interrupt_handler() {
+    free(|cs| {
+        let some_usart = G_USART1.borrow(cs).borrow();
+        let usart = some_usart.as_ref().unwrap();
+        let sr = usart.sr.read();
+        // Reading DR after SR also clears an overrun, the byte is dropped if the buffer is full
+        if sr.rxne().bit_is_set() || sr.ore().bit_is_set() {
+            let byte = usart.dr.read().bits() as u8;
+            G_RX.borrow(cs).borrow_mut().push(byte);
+        }
+        if sr.txe().bit_is_set() && usart.cr1.read().txeie().bit_is_set() {
+            match G_TX.borrow(cs).borrow_mut().pop() {
+                Some(byte) => usart.dr.write(|w| unsafe { w.bits(byte as u32) }),
+                None => usart.cr1.modify(|_, w| w.txeie().clear_bit()),
+            }
+        }
+    });
+}
@@ -0,0 +1,134 @@
+use crate::distance::DistanceStatus;
+use crate::pid::Pid;
+use crate::robot::{Encoders, MotorControl, Robot};
+
+/// Beyond this much further than the target distance the wall is considered to end.
+const WALL_LOST_MARGIN_CM: u16 = 20;
+/// Slowest wheel while rounding an outside corner, as a fraction of the base duty.
+const OUTSIDE_CORNER_INNER_WHEEL: f32 = 0.3;
+
+/// Follows the wall on the left at a target distance, steering with a PID on the distance error.
+/// A wall ahead is an inside corner, turned on the spot, and losing the wall is an outside corner, followed around.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WallFollower {
+    pid: Pid,
+    target_cm: u16,
+    base_duty: u16,
+    last_us: Option<u32>,
+}
+
+impl Default for WallFollower {
+    fn default() -> Self {
+        Self::new(3_000.0, 0.0, 6.0, 20, 45_000)
+    }
+}
+
+impl WallFollower {
+    /// The output of the PID is the duty added to the right wheel and subtracted from the left one,
+    /// with the gains in seconds.
+    pub const fn new(kp: f32, ki: f32, kd: f32, target_cm: u16, base_duty: u16) -> Self {
+        Self {
+            pid: Pid::new(kp, ki, kd),
+            target_cm,
+            base_duty,
+            last_us: None,
+        }
+    }
+
+    pub fn set_gains(&mut self, kp: f32, ki: f32, kd: f32) {
+        self.pid.set_gains(kp, ki, kd);
+    }
+
+    pub fn set_target_cm(&mut self, target_cm: u16) {
+        self.target_cm = target_cm;
+    }
+
+    pub fn set_base_duty(&mut self, base_duty: u16) {
+        self.base_duty = base_duty;
+    }
+
+    /// Steer along the wall. The PID is stepped by the time since the previous call,
+    /// taken from `SensorReadings::time_us`.
+    pub fn update<M: MotorControl, E: Encoders>(&mut self, robot: &mut Robot<M, E>) {
+        let sr = robot.get_sensor_readings().clone();
+        let dt_us = self.last_us.map_or(0, |last| sr.time_us.wrapping_sub(last));
+        self.last_us = Some(sr.time_us);
+        let base = self.base_duty as f32;
+        let (left, right) = if !sr.front_status.is_known() || !sr.left_status.is_known() {
+            self.pid.reset();
+            (0.0, 0.0)
+        } else if sr.front_status == DistanceStatus::TooClose
+            || sr.front_status == DistanceStatus::Valid && sr.front_distance <= self.target_cm
+        {
+            // Inside corner, turn right until the wall ahead is on the left
+            self.pid.reset();
+            (base, -base)
+        } else if sr.left_status == DistanceStatus::OutOfRange
+            || sr.left_distance > self.target_cm.saturating_add(WALL_LOST_MARGIN_CM)
+        {
+            // Outside corner, arc left around the end of the wall
+            self.pid.reset();
+            (base * OUTSIDE_CORNER_INNER_WHEEL, base)
+        } else {
+            let max_duty = robot.left_motor().get_max_duty() as f32;
+            self.pid.set_limits(-max_duty, max_duty);
+            // Too close to measure is as close as it gets
+            let left_distance = match sr.left_status {
+                DistanceStatus::TooClose => 0,
+                _ => sr.left_distance,
+            };
+            let error = left_distance as f32 - self.target_cm as f32;
+            let steering = self.pid.update(error, dt_us as f32 / 1e6);
+            (base - steering, base + steering)
+        };
+        robot.left_motor().set_speed(left as i32);
+        robot.right_motor().set_speed(right as i32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::mock::{MockEncoders, MockMotor, MockWheel};
+    use crate::robot::SensorReadings;
+
+    /// The right duty added after the wall came 4 cm closer than the target in `dt_us`.
+    fn steering_after(dt_us: u32) -> i32 {
+        let (left, right) = (RefCell::default(), RefCell::<MockWheel>::default());
+        let mut robot = Robot::new(
+            MockMotor::new(&left),
+            MockMotor::new(&right),
+            MockEncoders {
+                left: &left,
+                right: &right,
+            },
+        );
+        let mut follower = WallFollower::new(0.0, 0.0, 100.0, 20, 30_000);
+        let mut sr = SensorReadings {
+            front_distance: 400,
+            left_distance: 20,
+            front_status: DistanceStatus::Valid,
+            left_status: DistanceStatus::Valid,
+            time_us: 1_000_000,
+            ..Default::default()
+        };
+        robot.update_sensors(sr.clone());
+        follower.update(&mut robot);
+        // Nothing to take the derivative from on the first update
+        assert_eq!(robot.right_motor().get_speed(), 30_000);
+
+        sr.left_distance = 16;
+        sr.time_us += dt_us;
+        robot.update_sensors(sr);
+        follower.update(&mut robot);
+        30_000 - robot.right_motor().get_speed()
+    }
+
+    #[test]
+    fn derivative_follows_the_time_between_updates() {
+        assert_eq!(steering_after(15_625), 25_600);
+        assert_eq!(steering_after(31_250), 12_800);
+    }
+}